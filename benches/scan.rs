@@ -0,0 +1,85 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use serious_organizer_lib::dir_search::{ScanConfig, Scanner};
+use serious_organizer_lib::models::Location;
+use serious_organizer_lib::store::Store;
+use std::fs;
+use std::path::Path;
+
+const LOCATION_COUNT: usize = 4;
+const ENTRY_COUNT: usize = 50;
+const DIR_COUNT: usize = 3;
+const FILE_COUNT: usize = 10;
+
+/// Writes `LOCATION_COUNT` locations below `root`, each with `ENTRY_COUNT` entries of
+/// `DIR_COUNT` directories holding `FILE_COUNT` small files.
+fn generate_tree(root: &Path) {
+    for loc in 0..LOCATION_COUNT {
+        for entry in 0..ENTRY_COUNT {
+            for dir in 0..DIR_COUNT {
+                let path = root.join(format!("location {loc}/entry {entry}/dir {dir}"));
+                fs::create_dir_all(&path).unwrap();
+
+                for file in 0..FILE_COUNT {
+                    fs::write(path.join(format!("file {file}.bin")), vec![0; file]).unwrap();
+                }
+            }
+        }
+    }
+}
+
+/// The generated locations as stored by `Store`.
+fn setup(dir: &tempfile::TempDir) -> Vec<Location> {
+    let root = dir.path().join("tree");
+    generate_tree(&root);
+
+    let db = dir.path().join("bench.sqlite3");
+    let mut store = Store::init(db.to_str().unwrap()).unwrap();
+    for loc in 0..LOCATION_COUNT {
+        let path = root.join(format!("location {loc}"));
+        store
+            .add_location(&format!("location {loc}"), path.to_str().unwrap())
+            .unwrap();
+    }
+
+    store.get_locations().unwrap()
+}
+
+fn bench_scan(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let locations = setup(&dir);
+
+    let mut group = c.benchmark_group("get_all_data");
+    group.sample_size(20);
+
+    for threads in [1, 2, LOCATION_COUNT] {
+        let scanner = Scanner::new().config(ScanConfig {
+            threads,
+            group_by_device: false,
+            sniff_mime: false,
+        });
+        group.bench_with_input(BenchmarkId::new("threads", threads), &scanner, |b, s| {
+            b.iter(|| s.get_all_data(&locations))
+        });
+    }
+
+    let scanner = Scanner::new().config(ScanConfig {
+        threads: LOCATION_COUNT,
+        group_by_device: false,
+        sniff_mime: true,
+    });
+    group.bench_function("sniff_mime", |b| {
+        b.iter(|| scanner.get_all_data(&locations))
+    });
+
+    // Everything is on one device, so this is a serial scan
+    let scanner = Scanner::new();
+    group.bench_function("group_by_device", |b| {
+        b.iter(|| scanner.get_all_data(&locations))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_scan);
+criterion_main!(benches);
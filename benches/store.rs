@@ -0,0 +1,99 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use serious_organizer_lib::models::{DirEntry, FileEntry};
+use serious_organizer_lib::store::Store;
+use std::path::PathBuf;
+
+const ENTRY_COUNT: usize = 1000;
+
+fn dir_entry(ix: usize) -> DirEntry {
+    let path = PathBuf::from(format!("/bench/entry {}", ix));
+    let file_path = path.join("file.bin");
+
+    DirEntry {
+        name: format!("entry {}", ix),
+        location_id: 1,
+        path: path.clone(),
+        files: vec![FileEntry {
+            name: "file.bin".to_string(),
+            path: file_path,
+            size: ix as u64,
+            created: None,
+            modified: None,
+            symlink_target: None,
+            inode: None,
+            hard_link: false,
+            extension: None,
+            mime: None,
+            mode: None,
+            owner: None,
+        }],
+        size: ix as u64,
+        dirs: vec![(path, 0)],
+        created: None,
+        modified: None,
+    }
+}
+
+/// Store with a location, `ENTRY_COUNT` entries and one label.
+fn setup(dir: &tempfile::TempDir) -> Store {
+    let db = dir.path().join("bench.sqlite3");
+    let mut store = Store::init(db.to_str().unwrap()).unwrap();
+    store.add_location("bench", "/bench").unwrap();
+
+    let location_id = store.get_locations().unwrap()[0].id;
+    let data: Vec<_> = (0..ENTRY_COUNT)
+        .map(|ix| (location_id, dir_entry(ix)))
+        .collect();
+    store.update(&data, &[]).unwrap();
+    store.add_label("bench").unwrap();
+    store.load_from_store().unwrap();
+
+    store
+}
+
+fn bench_store(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = setup(&dir);
+
+    let label_id = store.get_all_labels()[0].id;
+    let entry = store.get_all_entries()[0].clone();
+    let entry_ids: Vec<i32> = store.get_all_entries().iter().map(|e| e.id).collect();
+
+    c.bench_function("establish_connection", |b| {
+        b.iter(|| store.establish_connection().unwrap())
+    });
+
+    c.bench_function("toggle_label", |b| {
+        b.iter(|| {
+            store
+                .add_entry_labels(vec![entry.id], vec![label_id])
+                .unwrap();
+            store
+                .remove_entry_labels(vec![entry.id], vec![label_id])
+                .unwrap();
+        })
+    });
+
+    c.bench_function("toggle_label_all_entries", |b| {
+        b.iter(|| {
+            store
+                .add_entry_labels(entry_ids.clone(), vec![label_id])
+                .unwrap();
+            store
+                .remove_entry_labels(entry_ids.clone(), vec![label_id])
+                .unwrap();
+        })
+    });
+
+    let mut grade = 0;
+    c.bench_function("set_grade", |b| {
+        b.iter(|| {
+            grade = (grade + 1) % 5;
+            store.set_grade(entry.clone(), grade).unwrap();
+        })
+    });
+}
+
+criterion_group!(benches, bench_store);
+criterion_main!(benches);
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE dir_mtimes (
+    id INTEGER PRIMARY KEY NOT NULL,
+    entry_id INTEGER NOT NULL,
+    path TEXT NOT NULL,
+    mtime BigInt NOT NULL,
+    FOREIGN KEY(entry_id) REFERENCES entries(id) ON DELETE CASCADE
+);
+
+CREATE INDEX dir_mtimes_entry_id ON dir_mtimes(entry_id);
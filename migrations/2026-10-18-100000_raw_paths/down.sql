@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE dir_mtimes DROP COLUMN path_raw;
+ALTER TABLE files DROP COLUMN path_raw;
+ALTER TABLE entries DROP COLUMN path_raw;
@@ -0,0 +1,4 @@
+-- Raw bytes of paths that are not valid UTF-8, `path` then only holds a lossy display version
+ALTER TABLE entries ADD path_raw BLOB;
+ALTER TABLE files ADD path_raw BLOB;
+ALTER TABLE dir_mtimes ADD path_raw BLOB;
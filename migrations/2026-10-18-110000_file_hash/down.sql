@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX files_hash;
+
+ALTER TABLE files DROP COLUMN hash;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE files ADD hash TEXT;
+
+CREATE INDEX files_hash ON files(hash);
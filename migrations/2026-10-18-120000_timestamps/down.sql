@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE entries DROP COLUMN created;
+ALTER TABLE entries DROP COLUMN modified;
+
+ALTER TABLE files DROP COLUMN created;
+ALTER TABLE files DROP COLUMN modified;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+ALTER TABLE entries ADD created BIGINT;
+ALTER TABLE entries ADD modified BIGINT;
+
+ALTER TABLE files ADD created BIGINT;
+ALTER TABLE files ADD modified BIGINT;
+
+-- Forget recorded directory mtimes so the next incremental scan fills in the timestamps
+DELETE FROM dir_mtimes;
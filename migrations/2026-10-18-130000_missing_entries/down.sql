@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX entries_missing_since;
+
+ALTER TABLE entries DROP COLUMN missing_since;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE entries ADD missing_since BIGINT;
+
+CREATE INDEX entries_missing_since ON entries(missing_since);
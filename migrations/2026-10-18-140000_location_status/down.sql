@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE locations DROP COLUMN last_error;
+ALTER TABLE locations DROP COLUMN scan_duration;
+ALTER TABLE locations DROP COLUMN last_scan;
+ALTER TABLE locations DROP COLUMN online;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE locations ADD online BOOLEAN NOT NULL DEFAULT 1;
+ALTER TABLE locations ADD last_scan BIGINT;
+ALTER TABLE locations ADD scan_duration BIGINT;
+ALTER TABLE locations ADD last_error TEXT;
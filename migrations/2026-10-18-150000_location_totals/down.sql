@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE locations DROP COLUMN file_count;
+ALTER TABLE locations DROP COLUMN entry_count;
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+ALTER TABLE locations ADD entry_count BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE locations ADD file_count BIGINT NOT NULL DEFAULT 0;
+
+UPDATE locations SET
+  size = (SELECT COALESCE(SUM(size), 0) FROM entries
+          WHERE entries.location_id = locations.id AND missing_since IS NULL),
+  entry_count = (SELECT COUNT(*) FROM entries
+                 WHERE entries.location_id = locations.id AND missing_since IS NULL),
+  file_count = (SELECT COUNT(*) FROM files INNER JOIN entries ON files.entry_id = entries.id
+                WHERE entries.location_id = locations.id AND missing_since IS NULL);
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE locations ADD entry_depth INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE locations ADD file_entries BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE locations DROP COLUMN exclude_globs;
+ALTER TABLE locations DROP COLUMN include_globs;
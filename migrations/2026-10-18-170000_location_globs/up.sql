@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE locations ADD include_globs TEXT NOT NULL DEFAULT '';
+ALTER TABLE locations ADD exclude_globs TEXT NOT NULL DEFAULT '';
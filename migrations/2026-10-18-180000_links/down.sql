@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE files DROP COLUMN hard_link;
+ALTER TABLE files DROP COLUMN inode;
+ALTER TABLE files DROP COLUMN symlink_target;
+
+ALTER TABLE locations DROP COLUMN symlinks;
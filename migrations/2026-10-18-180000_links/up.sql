@@ -0,0 +1,9 @@
+-- Your SQL goes here
+ALTER TABLE locations ADD symlinks INTEGER NOT NULL DEFAULT 1;
+
+ALTER TABLE files ADD symlink_target TEXT;
+ALTER TABLE files ADD inode BIGINT;
+ALTER TABLE files ADD hard_link BOOLEAN NOT NULL DEFAULT 0;
+
+-- Files are rescanned to fill in the link info
+DELETE FROM dir_mtimes;
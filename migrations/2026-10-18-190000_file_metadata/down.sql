@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE files DROP COLUMN owner;
+ALTER TABLE files DROP COLUMN mode;
+ALTER TABLE files DROP COLUMN mime;
+ALTER TABLE files DROP COLUMN extension;
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+ALTER TABLE files ADD extension TEXT;
+ALTER TABLE files ADD mime TEXT;
+ALTER TABLE files ADD mode INTEGER;
+ALTER TABLE files ADD owner BIGINT;
+
+-- Files are rescanned to fill in the new columns
+DELETE FROM dir_mtimes;
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX files_entry_id;
+DROP INDEX entries_path;
+DROP INDEX entries_scan_generation;
+
+ALTER TABLE entries DROP COLUMN scan_generation;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE entries ADD scan_generation INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX entries_scan_generation ON entries(location_id, scan_generation);
+CREATE INDEX entries_path ON entries(path);
+CREATE INDEX files_entry_id ON files(entry_id);
@@ -1,22 +1,26 @@
 #![allow(unused_imports)]
 use log::{error, info, trace, warn};
-use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::fs::Metadata;
-use std::mem;
-use std::path::PathBuf;
+use std::io::{self, ErrorKind};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
-use time::Instant;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::models::*;
-use jwalk::WalkDir;
+use crate::path_filter::PathFilter;
+use jwalk::{Parallelism, WalkDir};
 
 impl Drop for DirEntry {
     fn drop(&mut self) {
         self.files.clear();
-        mem::drop(&self.files);
     }
 }
 
@@ -28,7 +32,7 @@ impl Ord for DirEntry {
 
 impl PartialOrd for DirEntry {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.name.cmp(&other.name))
+        Some(self.cmp(other))
     }
 }
 
@@ -40,169 +44,1505 @@ impl PartialEq for DirEntry {
 
 impl Eq for DirEntry {}
 
-fn list_files_in_dir(location_id: i32, path: &str) -> Vec<DirEntry> {
-    // trace!("Starting glob: {:?}", path);
+/// Called from the scanning threads with the counts of one location so far.
+pub type ProgressFn<'a> = dyn Fn(&ScanProgress) + Sync + 'a;
 
-    let mut vec: Vec<DirEntry> = Vec::new();
+/// Progress callbacks are sent at most this often per location, except the final one.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(50);
 
-    let current_dir: RefCell<Option<DirEntry>> = RefCell::new(None);
+/// Stops a running scan from another thread, clones share the same state.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    parent: Option<Arc<AtomicBool>>,
+}
 
-    for entry in WalkDir::new(path)
-        .sort(true)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        let meta = get_meta(&entry.path());
-
-        // *** Handle file ***
-        if meta.is_file() && entry.depth() == 1 {
-            // println!("Found root file {:?} {}", entry.path(), entry.depth);
-            let file_name = entry
-                .file_name()
-                .to_str()
-                .expect("Failed to convert filename to rust string")
-                .to_string();
-            let path = entry
-                .path()
-                .to_str()
-                .expect("Failed to convert filepath to rust string")
-                .to_string();
-
-            let ff = vec![FileEntry {
-                name: file_name.clone(),
-                path: path.clone(),
-                size: meta.len(),
-            }];
-
-            let e = DirEntry {
-                location_id,
-                name: file_name,
-                path: path,
-                files: ff,
-                size: meta.len(),
-            };
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
 
-            vec.push(e);
-        } else if meta.is_file() && entry.depth() > 0 {
-            // Add files to dir entry
-            // println!("Found file {:?} ", entry.path());
-
-            let path = entry
-                .path()
-                .to_str()
-                .expect("Failed to convert filepath to rust string")
-                .to_string();
-
-            let ff = FileEntry {
-                name: entry
-                    .file_name()
-                    .to_str()
-                    .expect("Failed to convert filename to rust string")
-                    .to_string(),
-                path: path,
-                size: meta.len(),
+    /// A token that is also cancelled by this one, cancelling it leaves this one as it is.
+    pub fn child(&self) -> Self {
+        CancelToken {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            parent: Some(self.cancelled.clone()),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, AtomicOrdering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(AtomicOrdering::Relaxed)
+            || self
+                .parent
+                .as_ref()
+                .is_some_and(|parent| parent.load(AtomicOrdering::Relaxed))
+    }
+}
+
+/// How many locations are walked at the same time. Every location is walked on a single
+/// thread, so at most `threads` threads read from disk.
+#[derive(Clone, Debug)]
+pub struct ScanConfig {
+    /// Locations scanned in parallel, at least one
+    pub threads: usize,
+    /// Locations on the same device (`st_dev`) are scanned one after the other, so a spinning
+    /// disk is not read in several places at once. Has no effect outside unix.
+    pub group_by_device: bool,
+    /// Reads the first bytes of every scanned file to detect its MIME type, see
+    /// `FileEntry::mime`. Off by default, the store keeps the types of unchanged files from
+    /// an earlier scan.
+    pub sniff_mime: bool,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        ScanConfig {
+            threads: thread::available_parallelism().map_or(4, |n| n.get()),
+            group_by_device: true,
+            sniff_mime: false,
+        }
+    }
+}
+
+/// Scans locations with progress reporting and cancellation, `get_all_data` and
+/// `get_changed_data` use one with the default config and neither.
+///
+/// `Scanner::new().progress(&send_progress).cancel_token(token.clone()).get_all_data(&locations)`
+#[derive(Clone, Default)]
+pub struct Scanner<'a> {
+    config: ScanConfig,
+    progress: Option<&'a ProgressFn<'a>>,
+    cancel: CancelToken,
+    full_walk: bool,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new() -> Self {
+        Scanner::default()
+    }
+
+    pub fn config(mut self, config: ScanConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn progress(mut self, progress: &'a ProgressFn<'a>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// A cancelled scan returns what it found so far, the reports of the locations it did not
+    /// finish have `ScanReport::cancelled` set.
+    pub fn cancel_token(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Makes `get_changed_data` walk every entry, not only those with changed directory
+    /// mtimes. Finds files that were edited in place, which only change their own mtime.
+    pub fn full_walk(mut self, full_walk: bool) -> Self {
+        self.full_walk = full_walk;
+        self
+    }
+
+    /// Scans every entry of `locations`.
+    pub fn get_all_data(&self, locations: &[Location]) -> (Vec<(i32, DirEntry)>, Vec<ScanReport>) {
+        let start = Instant::now();
+
+        let delta = self.scan(locations, &HashMap::new());
+
+        info!(
+            "Got {:?} entries took: {:?} ms, walkdir",
+            delta.changed.len(),
+            start.elapsed().as_millis()
+        );
+
+        (delta.changed, delta.reports)
+    }
+
+    /// Like `get_all_data` but only walks entries that changed since the last scan according
+    /// to `known`, which maps location id to the directory mtimes stored for that location.
+    /// Files edited in place keep their directory mtimes, use `full_walk` to find them too.
+    pub fn get_changed_data(
+        &self,
+        locations: &[Location],
+        known: &HashMap<i32, KnownDirs>,
+    ) -> ScanDelta {
+        let start = Instant::now();
+
+        let delta = self.scan(locations, known);
+
+        info!(
+            "Got {:?} changed, {:?} removed and {:?} skipped entries took: {:?} ms",
+            delta.changed.len(),
+            delta.removed.len(),
+            delta.skipped.len(),
+            start.elapsed().as_millis()
+        );
+
+        delta
+    }
+
+    /// Scans every entry of `locations` like `get_all_data`, but sends each entry as soon as
+    /// it is scanned instead of collecting them. Each location ends with a
+    /// `ScanEvent::Finished` holding its report. With a bounded channel the scan waits for the
+    /// receiver, see `Store::update_streaming`. Dropping the receiver stops the scan, without
+    /// cancelling the scanner's `CancelToken`.
+    pub fn send_all_data(&self, locations: &[Location], sender: SyncSender<ScanEvent>) {
+        let start = Instant::now();
+        let empty = KnownDirs::new();
+        let stop = self.cancel.child();
+
+        self.run(locations, |location| {
+            let mut send = |dir| {
+                if sender.send(ScanEvent::Entry(location.id, dir)).is_err() {
+                    stop.cancel();
+                }
             };
+            let delta = self.scan_one(location, &empty, &stop, &mut send);
+
+            for report in delta.reports {
+                // Only fails if the receiver is gone
+                let _ = sender.send(ScanEvent::Finished(report));
+            }
+        });
+
+        info!(
+            "Sent all entries, took: {:?} ms",
+            start.elapsed().as_millis()
+        );
+    }
+
+    fn scan(&self, locations: &[Location], known: &HashMap<i32, KnownDirs>) -> ScanDelta {
+        let empty = KnownDirs::new();
+        let results = Mutex::new(Vec::new());
+
+        self.run(locations, |location| {
+            let known = known.get(&location.id).unwrap_or(&empty);
+            let mut changed = Vec::new();
+
+            let mut res = self.scan_one(location, known, &self.cancel, &mut |dir| {
+                changed.push((location.id, dir));
+            });
+            res.changed = changed;
+            results.lock().unwrap().push(res);
+        });
+
+        let mut delta = ScanDelta::default();
+        for mut res in results.into_inner().unwrap() {
+            delta.changed.append(&mut res.changed);
+            delta.removed.append(&mut res.removed);
+            delta.skipped.append(&mut res.skipped);
+            delta.reports.append(&mut res.reports);
+        }
+
+        delta.changed.sort();
+        delta.reports.sort_by_key(|r| r.location_id);
+        delta
+    }
+
+    /// Calls `scan` for every location on at most `config.threads` threads, the locations of a
+    /// group one after the other.
+    fn run(&self, locations: &[Location], scan: impl Fn(&Location) + Sync) {
+        let groups = self.groups(locations);
+        let workers = self.config.threads.clamp(1, groups.len().max(1));
+        let queue = Mutex::new(groups.into_iter());
+
+        thread::scope(|s| {
+            for _ in 0..workers {
+                s.spawn(|| {
+                    // Take the next group until none are left, the lock must not be held
+                    // while scanning
+                    loop {
+                        let next = queue.lock().unwrap().next();
+                        let Some(group) = next else { break };
+                        group.into_iter().for_each(&scan);
+                    }
+                });
+            }
+        });
+    }
 
-            if let Some(dir) = &mut *current_dir.borrow_mut() {
-                dir.size += ff.size;
-                dir.files.push(ff);
+    /// Scans a single location, a panic while walking it is reported as a failed scan.
+    fn scan_one(
+        &self,
+        location: &Location,
+        known: &KnownDirs,
+        cancel: &CancelToken,
+        emit: &mut dyn FnMut(DirEntry),
+    ) -> ScanDelta {
+        let start = Instant::now();
+        let mut state = ScanState::new(location.id, self.progress, cancel.clone());
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let (full_walk, sniff) = (self.full_walk, self.config.sniff_mime);
+            scan_location(location, known, full_walk, sniff, &mut state, emit)
+        }));
+        state.finish();
+
+        info!(
+            "Path {:?} entries took: {:?} ms",
+            location.path,
+            start.elapsed().as_millis()
+        );
+
+        res.unwrap_or_else(|_| {
+            error!("Scanning location {} panicked", location.id);
+            ScanDelta {
+                reports: vec![ScanReport::offline(location.id, "Scan failed unexpectedly")],
+                ..Default::default()
             }
-        } else
-        // *** Handle dir ***
-        if meta.is_dir() && entry.depth() == 1 {
-            // Create new dir entry to work in
-            // println!("Found root dir {:?} {}", entry.path(), entry.depth);
+        })
+    }
+
+    /// Locations that are scanned one after the other, in the order of `locations`.
+    fn groups<'l>(&self, locations: &'l [Location]) -> Vec<Vec<&'l Location>> {
+        let mut groups: Vec<(Option<u64>, Vec<&Location>)> = Vec::new();
 
-            let foo = current_dir.borrow_mut().take();
-            if let Some(f) = foo {
-                vec.push(f);
+        for location in locations {
+            let device = self
+                .config
+                .group_by_device
+                .then(|| device_id(Path::new(&location.path)))
+                .flatten();
+
+            match groups
+                .iter_mut()
+                .find(|(d, _)| device.is_some() && *d == device)
+            {
+                Some((_, group)) => group.push(location),
+                None => groups.push((device, vec![location])),
             }
+        }
 
-            // trace!("***");
+        groups.into_iter().map(|(_, group)| group).collect()
+    }
+}
 
-            let name = entry
-                .file_name()
-                .to_str()
-                .expect("Failed to convert dirname to rust string")
-                .to_string();
-            let path = entry
-                .path()
-                .to_str()
-                .expect("Failed to convert dirpath to rust string")
-                .to_string();
+/// Progress and cancellation of one location's scan.
+pub(crate) struct ScanState<'a> {
+    progress: ScanProgress,
+    callback: Option<&'a ProgressFn<'a>>,
+    cancel: CancelToken,
+    last_sent: Option<Instant>,
+}
 
-            let dir = DirEntry {
+impl<'a> ScanState<'a> {
+    pub(crate) fn new(
+        location_id: i32,
+        callback: Option<&'a ProgressFn<'a>>,
+        cancel: CancelToken,
+    ) -> Self {
+        ScanState {
+            progress: ScanProgress {
                 location_id,
-                name: name,
-                path: path,
-                files: Vec::new(),
-                size: 0,
+                ..Default::default()
+            },
+            callback,
+            cancel,
+            last_sent: None,
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    fn enter(&mut self, path: &Path) {
+        self.progress.current = path.to_path_buf();
+        self.send(false);
+    }
+
+    fn visit_dir(&mut self, path: &Path) {
+        self.progress.dirs += 1;
+        self.enter(path);
+    }
+
+    fn visit_file(&mut self, size: u64) {
+        self.progress.files += 1;
+        self.progress.bytes += size;
+        self.send(false);
+    }
+
+    fn finish(&mut self) {
+        self.progress.done = true;
+        self.send(true);
+    }
+
+    fn send(&mut self, force: bool) {
+        let callback = match self.callback {
+            Some(callback) => callback,
+            None => return,
+        };
+
+        if force
+            || self
+                .last_sent
+                .is_none_or(|sent| sent.elapsed() >= PROGRESS_INTERVAL)
+        {
+            self.last_sent = Some(Instant::now());
+            callback(&self.progress);
+        }
+    }
+}
+
+/// Walks all entries of a location. Entries whose recorded directory mtimes in `known` still
+/// match are skipped without reading their directories, unless `full_walk` is set. Scanned
+/// entries are passed to `emit` instead of being added to the result, with the types of their
+/// files if `sniff` is set. Nothing is reported removed if the scan is cancelled.
+fn scan_location(
+    location: &Location,
+    known: &KnownDirs,
+    full_walk: bool,
+    sniff: bool,
+    state: &mut ScanState,
+    emit: &mut dyn FnMut(DirEntry),
+) -> ScanDelta {
+    let location_id = location.id;
+    let path = location.path.as_str();
+
+    let start = Instant::now();
+    let mut delta = ScanDelta::default();
+    let mut report = ScanReport::new(location_id);
+    let mut seen = HashSet::new();
+    let filter = Arc::new(PathFilter::new(location));
+
+    let children = match find_entry_paths(location, &filter, Path::new(path), state, &mut report) {
+        Ok(children) => children,
+        Err(err) => {
+            error!("Failed to read location {:?}: {}", path, err);
+            report.skip(path, skip_reason(Path::new(path), &err));
+            report.offline = true;
+            report.error = Some(err.to_string());
+            delta.reports.push(report);
+            return delta;
+        }
+    };
+
+    for child in children {
+        if state.is_cancelled() {
+            break;
+        }
+        seen.insert(child.clone());
+
+        if let Some(dirs) = known.get(&child).filter(|_| !full_walk) {
+            if is_unchanged(dirs) {
+                delta.skipped.push(child);
+                continue;
+            }
+        }
+
+        let symlinks = location.symlink_policy();
+        if let Some(mut dir) =
+            scan_entry(location_id, &child, &filter, symlinks, state, &mut report)
+        {
+            if sniff {
+                sniff_types(&mut dir);
+            }
+            emit(dir);
+        }
+    }
+
+    if state.is_cancelled() {
+        info!("Scan of {:?} was cancelled", path);
+        report.cancelled = true;
+        delta.reports.push(report);
+        return delta;
+    }
+
+    delta.removed = known
+        .keys()
+        .filter(|p| !seen.contains(*p))
+        .cloned()
+        .collect();
+
+    if !report.is_empty() {
+        warn!(
+            "Skipped {} paths when scanning {:?}",
+            report.skipped.len(),
+            path
+        );
+    }
+    report.duration = Some(start.elapsed());
+    delta.reports.push(report);
+
+    delta
+}
+
+/// Paths of the entries at or below `start`, a directory inside `location`. Directories above
+/// the location's entry depth only group entries, files found there are entries themselves.
+/// Paths rejected by `filter` are left out. Followed links to a directory that was already
+/// walked are skipped. Fails only if `start` itself can not be read.
+pub(crate) fn find_entry_paths(
+    location: &Location,
+    filter: &PathFilter,
+    start: &Path,
+    state: &mut ScanState,
+    report: &mut ScanReport,
+) -> io::Result<Vec<PathBuf>> {
+    let start_depth = start
+        .strip_prefix(&location.path)
+        .map_or(0, |rel| rel.components().count() as i32);
+
+    let symlinks = location.symlink_policy();
+    let mut entries = Vec::new();
+    let mut containers = vec![(start.to_path_buf(), start_depth)];
+    let mut walked: HashSet<PathBuf> = fs::canonicalize(start).into_iter().collect();
+
+    while let Some((dir, depth)) = containers.pop() {
+        if state.is_cancelled() {
+            break;
+        }
+
+        let read_dir = match fs::read_dir(&dir) {
+            Ok(read_dir) => read_dir,
+            Err(err) if dir == start => return Err(err),
+            Err(err) => {
+                report.skip(&dir, skip_reason(&dir, &err));
+                continue;
+            }
+        };
+
+        for child in read_dir {
+            let child = match child {
+                Ok(child) => child.path(),
+                Err(err) => {
+                    report.skip(&dir, skip_reason(&dir, &err));
+                    continue;
+                }
             };
 
-            *current_dir.borrow_mut() = Some(dir);
+            if is_hidden(&child) || filter.is_excluded(&child) {
+                continue;
+            }
+
+            let is_link = get_link_meta(&child).is_ok_and(|meta| meta.file_type().is_symlink());
+            if is_link && symlinks == SymlinkPolicy::Ignore {
+                continue;
+            }
+
+            let is_dir = !(is_link && symlinks == SymlinkPolicy::Record)
+                && get_meta(&child).is_ok_and(|meta| meta.is_dir());
+            let grouping = location.file_entries || depth + 1 < location.entry_depth;
+            if is_dir && grouping {
+                let follows = symlinks == SymlinkPolicy::Follow;
+                if follows && !fs::canonicalize(&child).is_ok_and(|real| walked.insert(real)) {
+                    report.skip(&child, SkipReason::LinkLoop);
+                    continue;
+                }
+
+                containers.push((child, depth + 1));
+            } else if is_dir || filter.includes_file(&child) {
+                entries.push(child);
+            }
+        }
+    }
+
+    entries.sort();
+    Ok(entries)
+}
+
+/// Hidden files are skipped like `WalkDir` does for everything below the top level.
+pub(crate) fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with('.'))
+        .unwrap_or(false)
+}
+
+/// An entry is unchanged if none of its directories were modified, added files or sub
+/// directories always bump the mtime of their parent. Writing to an existing file does not, so
+/// files edited in place are missed.
+fn is_unchanged(dirs: &[(PathBuf, i64)]) -> bool {
+    !dirs.is_empty()
+        && dirs.iter().all(|(path, mtime)| match fs::metadata(path) {
+            Ok(meta) => get_mtime(&meta) == *mtime,
+            Err(_) => false,
+        })
+}
+
+/// Scans a single entry of a location, a file becomes an entry with itself as only file, a
+/// directory gets all files below it that pass `filter`. Files that are reached twice through
+/// links are only counted once in the entry size. Paths that can not be read are added to
+/// `report`.
+pub(crate) fn scan_entry(
+    location_id: i32,
+    path: &Path,
+    filter: &Arc<PathFilter>,
+    symlinks: SymlinkPolicy,
+    state: &mut ScanState,
+    report: &mut ScanReport,
+) -> Option<DirEntry> {
+    if filter.is_excluded(path) {
+        return None;
+    }
+    state.enter(path);
+
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    // *** Handle link ***
+    let link_meta = match get_link_meta(path) {
+        Ok(meta) => meta,
+        Err(err) => {
+            report.skip(path, skip_reason(path, &err));
+            return None;
+        }
+    };
+
+    if link_meta.file_type().is_symlink() && symlinks != SymlinkPolicy::Follow {
+        if symlinks == SymlinkPolicy::Ignore || !filter.includes_file(path) {
+            return None;
+        }
+
+        let file = link_entry(name.clone(), path.to_path_buf(), &link_meta);
+        state.visit_file(0);
+        return Some(single_file_entry(location_id, name, file, &link_meta));
+    }
+
+    let meta = match get_meta(path) {
+        Ok(meta) => meta,
+        Err(err) => {
+            report.skip(path, skip_reason(path, &err));
+            return None;
+        }
+    };
+
+    // *** Handle file ***
+    if meta.is_file() {
+        if !filter.includes_file(path) {
+            return None;
+        }
+
+        let file = file_entry(name.clone(), path.to_path_buf(), &meta);
+        state.visit_file(file.size);
+        return Some(single_file_entry(location_id, name, file, &meta));
+    }
+
+    if !meta.is_dir() {
+        return None;
+    }
+
+    // *** Handle dir ***
+    let (created, modified) = get_times(&meta);
+    let mut dir = DirEntry {
+        location_id,
+        name,
+        path: path.to_path_buf(),
+        files: Vec::new(),
+        size: 0,
+        dirs: vec![(path.to_path_buf(), get_mtime(&meta))],
+        created,
+        modified,
+    };
+
+    let walk_filter = Arc::clone(filter);
+    let walk = WalkDir::new(path)
+        .sort(true)
+        .parallelism(Parallelism::Serial)
+        .follow_links(symlinks == SymlinkPolicy::Follow)
+        .process_read_dir(move |_, _, _, children| {
+            // Excluded directories are not read at all
+            children.retain(|child| {
+                child
+                    .as_ref()
+                    .map_or(true, |child| !walk_filter.is_excluded(&child.path()))
+            });
+        });
+
+    let mut inodes = HashSet::new();
+    state.visit_dir(path);
+
+    for entry in walk.into_iter() {
+        // A partly walked entry is left out
+        if state.is_cancelled() {
+            return None;
+        }
+
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                let reason = match (err.io_error(), err.path()) {
+                    _ if err.loop_ancestor().is_some() => SkipReason::LinkLoop,
+                    (Some(io_err), Some(err_path)) => skip_reason(err_path, io_err),
+                    _ => SkipReason::Other(err.to_string()),
+                };
+                report.skip(err.path().unwrap_or(path), reason);
+                continue;
+            }
+        };
+
+        if entry.depth() == 0 {
+            continue;
+        }
+
+        let entry_path = entry.path();
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+
+        // Only links that are not followed are reported as links
+        if entry.file_type().is_symlink() {
+            if symlinks == SymlinkPolicy::Ignore || !filter.includes_file(&entry_path) {
+                continue;
+            }
+
+            match get_link_meta(&entry_path) {
+                Ok(link_meta) => {
+                    state.visit_file(0);
+                    dir.files
+                        .push(link_entry(file_name, entry_path, &link_meta));
+                }
+                Err(err) => {
+                    let reason = skip_reason(&entry_path, &err);
+                    report.skip(entry_path, reason);
+                }
+            }
+            continue;
+        }
+
+        let meta = match get_meta(&entry_path) {
+            Ok(meta) => meta,
+            Err(err) => {
+                let reason = skip_reason(&entry_path, &err);
+                report.skip(entry_path, reason);
+                continue;
+            }
+        };
+
+        if meta.is_file() {
+            if !filter.includes_file(&entry_path) {
+                continue;
+            }
+
+            // Add files to dir entry
+            let mut ff = file_entry(file_name, entry_path, &meta);
+            if let Some(key) = inode_key(&meta) {
+                ff.hard_link = !inodes.insert(key);
+            }
+
+            let size = if ff.hard_link { 0 } else { ff.size };
+            dir.size += size;
+            state.visit_file(size);
+            dir.modified = dir.modified.max(ff.modified);
+            dir.files.push(ff);
+        } else if meta.is_dir() {
+            state.visit_dir(&entry_path);
+            dir.dirs.push((entry_path, get_mtime(&meta)));
         }
     }
 
-    let foo = current_dir.borrow_mut().take();
-    if let Some(f) = foo {
-        vec.push(f);
+    Some(dir)
+}
+
+/// Entry for a single file, its modification time is recorded like a directory's.
+fn single_file_entry(location_id: i32, name: String, file: FileEntry, meta: &Metadata) -> DirEntry {
+    DirEntry {
+        location_id,
+        name,
+        path: file.path.clone(),
+        size: file.size,
+        dirs: vec![(file.path.clone(), get_mtime(meta))],
+        created: file.created,
+        modified: file.modified,
+        files: vec![file],
+    }
+}
+
+fn file_entry(name: String, path: PathBuf, meta: &Metadata) -> FileEntry {
+    let (created, modified) = get_times(meta);
+    let (mode, owner) = permissions(meta);
+
+    FileEntry {
+        name,
+        extension: extension(&path),
+        mime: None,
+        path,
+        size: meta.len(),
+        created,
+        modified,
+        symlink_target: None,
+        inode: inode_key(meta).map(|(_, inode)| inode),
+        hard_link: false,
+        mode,
+        owner,
     }
+}
+
+/// A symbolic link that is recorded instead of followed, it takes up no space.
+fn link_entry(name: String, path: PathBuf, link_meta: &Metadata) -> FileEntry {
+    let (created, modified) = get_times(link_meta);
+    let (mode, owner) = permissions(link_meta);
+
+    FileEntry {
+        name,
+        symlink_target: fs::read_link(&path).ok(),
+        extension: extension(&path),
+        mime: None,
+        path,
+        size: 0,
+        created,
+        modified,
+        inode: None,
+        hard_link: false,
+        mode,
+        owner,
+    }
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+}
+
+/// Sets the MIME type of every file that is not a recorded link.
+fn sniff_types(dir: &mut DirEntry) {
+    for file in dir.files.iter_mut() {
+        if file.symlink_target.is_none() {
+            file.mime = sniff_mime(&file.path);
+        }
+    }
+}
+
+/// MIME type from the magic bytes at the start of the file, an unreadable file has none.
+fn sniff_mime(path: &Path) -> Option<String> {
+    match infer::get_from_path(long_path(path)) {
+        Ok(kind) => kind.map(|kind| kind.mime_type().to_string()),
+        Err(err) => {
+            trace!("Could not read {:?} to detect its type: {}", path, err);
+            None
+        }
+    }
+}
+
+/// Permission bits and owner user id.
+#[cfg(unix)]
+fn permissions(meta: &Metadata) -> (Option<u32>, Option<u32>) {
+    use std::os::unix::fs::MetadataExt;
+    (Some(meta.mode() & 0o7777), Some(meta.uid()))
+}
+
+#[cfg(not(unix))]
+fn permissions(_meta: &Metadata) -> (Option<u32>, Option<u32>) {
+    (None, None)
+}
 
-    vec
+/// Device and inode number, identical for all hard links of a file.
+#[cfg(unix)]
+fn inode_key(meta: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
 }
 
+#[cfg(not(unix))]
+fn inode_key(_meta: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Device the location is stored on, `None` if it can not be read.
+#[cfg(unix)]
+fn device_id(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|meta| meta.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_path: &Path) -> Option<u64> {
+    None
+}
+
+fn skip_reason(path: &Path, err: &io::Error) -> SkipReason {
+    match err.kind() {
+        ErrorKind::PermissionDenied => SkipReason::PermissionDenied,
+        ErrorKind::NotFound => {
+            // A link that still exists but points nowhere
+            if fs::symlink_metadata(path).is_ok() {
+                SkipReason::BrokenLink
+            } else {
+                SkipReason::Vanished
+            }
+        }
+        _ => SkipReason::Other(err.to_string()),
+    }
+}
+
+/// Created and modified time in seconds since unix epoch.
+fn get_times(meta: &Metadata) -> (Option<i64>, Option<i64>) {
+    let seconds = |time: io::Result<SystemTime>| {
+        time.ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|dur| dur.as_secs() as i64)
+    };
+
+    (seconds(meta.created()), seconds(meta.modified()))
+}
+
+/// Modification time in nanoseconds since unix epoch, 0 if the platform does not support it.
+fn get_mtime(meta: &Metadata) -> i64 {
+    meta.modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|dur| dur.as_nanos() as i64)
+        .unwrap_or(0)
+}
+
+#[inline]
+fn get_meta(dir_path: &Path) -> io::Result<Metadata> {
+    fs::metadata(long_path(dir_path))
+}
+
+/// Like `get_meta` but for the link itself if `path` is a symbolic link.
 #[inline]
-fn get_meta(dir_path: &PathBuf) -> Metadata {
+fn get_link_meta(path: &Path) -> io::Result<Metadata> {
+    fs::symlink_metadata(long_path(path))
+}
+
+fn long_path(path: &Path) -> PathBuf {
     if cfg!(windows) {
-        let dir_path = dir_path.to_str().expect("Failed to read path");
+        let path = path.to_string_lossy();
 
-        if dir_path.len() >= 260 {
-            let strr = "\\??\\".to_owned() + dir_path;
-            fs::metadata(&strr).expect("failed to read metadata")
-        } else {
-            fs::metadata(&dir_path).expect("failed to read metadata")
+        if path.len() >= 260 {
+            return PathBuf::from("\\??\\".to_owned() + &path);
         }
-    } else {
-        fs::metadata(&dir_path).expect("failed to read metadata")
     }
+
+    path.to_path_buf()
 }
 
-pub fn get_all_data(paths: &Vec<(i32, String)>) -> Vec<(i32, DirEntry)> {
-    let mut vec = Vec::new();
+/// Scans every entry of `locations`, see `Scanner` for progress and cancellation.
+pub fn get_all_data(locations: &[Location]) -> (Vec<(i32, DirEntry)>, Vec<ScanReport>) {
+    Scanner::new().get_all_data(locations)
+}
 
-    let start = Instant::now();
+/// Like `get_all_data` but only walks entries that changed since the last scan according to
+/// `known`, which maps location id to the directory mtimes stored for that location. See
+/// `Scanner::full_walk` for files edited in place.
+pub fn get_changed_data(locations: &[Location], known: &HashMap<i32, KnownDirs>) -> ScanDelta {
+    Scanner::new().get_changed_data(locations, known)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Store;
+    use std::sync::{mpsc, Condvar};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    fn location(path: &Path) -> Location {
+        Location {
+            id: 1,
+            name: "loc".to_string(),
+            path: path.to_str().unwrap().to_string(),
+            size: 0,
+            online: true,
+            last_scan: None,
+            scan_duration: None,
+            last_error: None,
+            entry_count: 0,
+            file_count: 0,
+            entry_depth: 1,
+            file_entries: false,
+            include_globs: String::new(),
+            exclude_globs: String::new(),
+            symlinks: SymlinkPolicy::Follow as i32,
+        }
+    }
+
+    /// Scan state without progress callback or cancellation.
+    fn silent(location: &Location) -> ScanState<'static> {
+        ScanState::new(location.id, None, CancelToken::new())
+    }
+
+    /// Scans `location` on the calling thread.
+    fn scan(location: &Location, known: &KnownDirs) -> ScanDelta {
+        let mut changed = Vec::new();
+        let mut delta = scan_location(
+            location,
+            known,
+            false,
+            false,
+            &mut silent(location),
+            &mut |dir| changed.push((location.id, dir)),
+        );
+        delta.changed = changed;
+        delta
+    }
+
+    fn list_files_in_dir(location: &Location) -> (Vec<DirEntry>, ScanReport) {
+        let delta = scan(location, &KnownDirs::new());
+        let report = delta
+            .reports
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| ScanReport::new(location.id));
+
+        (delta.changed.into_iter().map(|(_, d)| d).collect(), report)
+    }
+
+    fn known_from(entries: &[DirEntry]) -> KnownDirs {
+        entries
+            .iter()
+            .map(|d| (d.path.clone(), d.dirs.clone()))
+            .collect()
+    }
 
-    let mut children = Vec::new();
+    #[test]
+    fn unchanged_subtrees_are_not_walked() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("a/sub")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a/sub/x.txt"), "x").unwrap();
+        fs::write(root.join("b/y.txt"), "y").unwrap();
+        fs::write(root.join("c.txt"), "c").unwrap();
 
-    for p in paths.iter().cloned() {
-        children.push(thread::spawn(move || {
-            let start = Instant::now();
+        let location = location(root);
+        let (first, report) = list_files_in_dir(&location);
+        assert_eq!(first.len(), 3);
+        assert!(report.is_empty());
 
-            let vec1 = list_files_in_dir(p.0, &p.1)
-                .into_iter()
-                .map(|d| (p.0, d))
-                .collect();
+        // Change something deep inside `a` and remove `c.txt`
+        sleep(Duration::from_millis(20));
+        fs::write(root.join("a/sub/z.txt"), "zz").unwrap();
+        fs::remove_file(root.join("c.txt")).unwrap();
 
-            info!(
-                "Path {:?} entries took: {:?} ms",
-                &p.1,
-                start.elapsed().whole_milliseconds()
-            );
-            vec1
-        }))
+        let delta = scan(&location, &known_from(&first));
+
+        assert_eq!(delta.changed.len(), 1);
+        let a = &delta.changed[0].1;
+        assert_eq!(a.name, "a");
+        assert_eq!(a.files.len(), 2);
+        assert_eq!(a.size, 3);
+        assert_eq!(delta.removed, vec![root.join("c.txt")]);
+        assert_eq!(delta.skipped, vec![root.join("b")]);
+    }
+
+    #[test]
+    fn incremental_update_keeps_skipped_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("loc");
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a/x.txt"), "x").unwrap();
+        fs::write(root.join("b/y.txt"), "y").unwrap();
+
+        let db = tmp.path().join("test.sqlite3");
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+        store.add_location("loc", root.to_str().unwrap()).unwrap();
+        store.load_from_store().unwrap();
+        let location = store.get_locations().unwrap()[0].clone();
+        let locations = vec![location.clone()];
+
+        let (data, report) = get_all_data(&locations);
+        store.update(&data, &report).unwrap();
+        assert_eq!(store.get_all_entries().len(), 2);
+
+        sleep(Duration::from_millis(20));
+        fs::write(root.join("b/w.txt"), "ww").unwrap();
+
+        let delta = get_changed_data(&locations, &store.get_known_dirs().unwrap());
+        assert_eq!(delta.changed.len(), 1);
+        assert_eq!(delta.skipped.len(), 1);
+
+        store.update_incremental(&delta).unwrap();
+
+        let entries = store.get_all_entries().clone();
+        assert_eq!(entries.len(), 2);
+        let b = entries.iter().find(|e| e.name == "b").unwrap();
+        assert_eq!(b.size, 3);
+        assert_eq!(store.get_files(b).unwrap().len(), 2);
+
+        // Nothing changed since the last update
+        let delta = get_changed_data(&locations, &store.get_known_dirs().unwrap());
+        assert!(delta.changed.is_empty());
+        assert_eq!(delta.skipped.len(), 2);
     }
 
-    for c in children {
-        vec.append(&mut c.join().expect("Failed to join thread!"));
+    #[test]
+    fn full_walks_find_files_edited_in_place() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("loc");
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("a/x.txt"), "x").unwrap();
+
+        let db = tmp.path().join("test.sqlite3");
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+        store.add_location("loc", root.to_str().unwrap()).unwrap();
+        let locations = store.get_locations().unwrap();
+
+        let (data, report) = get_all_data(&locations);
+        store.update(&data, &report).unwrap();
+
+        // Writing to an existing file leaves the directory mtime alone
+        sleep(Duration::from_millis(20));
+        fs::write(root.join("a/x.txt"), "xyz").unwrap();
+
+        let known = store.get_known_dirs().unwrap();
+        let delta = get_changed_data(&locations, &known);
+        assert!(delta.changed.is_empty());
+
+        let delta = Scanner::new()
+            .full_walk(true)
+            .get_changed_data(&locations, &known);
+        assert_eq!(delta.changed.len(), 1);
+        assert!(delta.skipped.is_empty());
+        assert!(delta.removed.is_empty());
+
+        store.update_incremental(&delta).unwrap();
+        assert_eq!(store.get_all_entries()[0].size, 3);
     }
 
-    vec.sort();
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_paths_are_reported() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("a/x.txt"), "x").unwrap();
+        std::os::unix::fs::symlink(root.join("a/missing"), root.join("a/broken")).unwrap();
+        std::os::unix::fs::symlink(root.join("missing"), root.join("dangling")).unwrap();
+
+        let (entries, report) = list_files_in_dir(&location(root));
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].files.len(), 1);
+
+        let mut skipped: Vec<_> = report
+            .skipped
+            .iter()
+            .map(|s| (s.path.clone(), s.reason.clone()))
+            .collect();
+        skipped.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            skipped,
+            vec![
+                (root.join("a/broken"), SkipReason::BrokenLink),
+                (root.join("dangling"), SkipReason::BrokenLink),
+            ]
+        );
+    }
 
-    info!(
-        "Got {:?} entries took: {:?} ms, walkdir",
-        vec.len(),
-        start.elapsed().whole_milliseconds()
-    );
+    #[test]
+    fn offline_locations_keep_their_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("drive");
+        let db = tmp.path().join("test.sqlite3");
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("a/x.txt"), "x").unwrap();
 
-    vec
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+        store.add_location("drive", root.to_str().unwrap()).unwrap();
+        let location = store.get_locations().unwrap()[0].clone();
+        assert!(location.online);
+        assert_eq!(location.last_scan, None);
+        let locations = vec![location.clone()];
+
+        let (data, reports) = get_all_data(&locations);
+        store.update(&data, &reports).unwrap();
+        let location = store.get_locations().unwrap()[0].clone();
+        assert!(location.online);
+        assert!(location.last_scan.is_some());
+        assert!(location.scan_duration.is_some());
+
+        // Unmounted
+        fs::rename(&root, tmp.path().join("elsewhere")).unwrap();
+        let (data, reports) = get_all_data(&locations);
+        assert!(data.is_empty());
+        assert!(reports[0].offline);
+        let report = store.update(&data, &reports).unwrap();
+        assert!(report.is_empty());
+
+        let entries = store.get_all_entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].missing_since, None);
+        let offline = store.get_locations().unwrap()[0].clone();
+        assert!(!offline.online);
+        assert!(offline.last_error.is_some());
+        assert_eq!(offline.last_scan, location.last_scan);
+
+        let delta = get_changed_data(&locations, &store.get_known_dirs().unwrap());
+        assert!(delta.changed.is_empty() && delta.removed.is_empty());
+        assert!(store.update_incremental(&delta).unwrap().is_empty());
+        assert_eq!(store.get_all_entries()[0].missing_since, None);
+
+        fs::rename(tmp.path().join("elsewhere"), &root).unwrap();
+        let delta = get_changed_data(&locations, &store.get_known_dirs().unwrap());
+        store.update_incremental(&delta).unwrap();
+        let location = store.get_locations().unwrap()[0].clone();
+        assert!(location.online);
+        assert_eq!(location.last_error, None);
+    }
+
+    #[test]
+    fn entries_follow_location_depth() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("music");
+        fs::create_dir_all(root.join("artist/album/cd1")).unwrap();
+        fs::create_dir_all(root.join("artist/other")).unwrap();
+        fs::create_dir_all(root.join(".cache/hidden")).unwrap();
+        fs::write(root.join("artist/album/1.flac"), "1").unwrap();
+        fs::write(root.join("artist/album/cd1/2.flac"), "22").unwrap();
+        fs::write(root.join("artist/other/3.flac"), "333").unwrap();
+        fs::write(root.join("artist/cover.jpg"), "c").unwrap();
+        fs::write(root.join("loose.flac"), "l").unwrap();
+
+        let mut albums = location(&root);
+        albums.entry_depth = 2;
+        let (entries, _) = list_files_in_dir(&albums);
+        let names: Vec<_> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["album", "cover.jpg", "other", "loose.flac"]);
+        assert_eq!(entries[0].files.len(), 2);
+
+        let mut files = location(&root);
+        files.file_entries = true;
+        let (entries, _) = list_files_in_dir(&files);
+        assert_eq!(entries.len(), 5);
+        assert!(entries.iter().all(|e| e.files.len() == 1));
+
+        // Changing the depth replaces the old entries instead of keeping them as missing
+        let db = tmp.path().join("test.sqlite3");
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+        store.add_location("music", root.to_str().unwrap()).unwrap();
+        let locations = store.get_locations().unwrap();
+        let (data, reports) = get_all_data(&locations);
+        store.update(&data, &reports).unwrap();
+        assert_eq!(store.get_all_entries().len(), 2);
+
+        store.set_entry_layout(locations[0].id, 2, false).unwrap();
+        assert!(store.set_entry_layout(locations[0].id, 0, false).is_err());
+        let locations = store.get_locations().unwrap();
+        let delta = get_changed_data(&locations, &store.get_known_dirs().unwrap());
+        store.update_incremental(&delta).unwrap();
+
+        let mut paths: Vec<_> = store
+            .get_all_entries()
+            .iter()
+            .map(|e| (e.fs_path(), e.missing_since))
+            .collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                (root.join("artist/album"), None),
+                (root.join("artist/cover.jpg"), None),
+                (root.join("artist/other"), None),
+                (root.join("loose.flac"), None),
+            ]
+        );
+
+        // Removing a directory above the entry depth removes the entries in it
+        fs::remove_dir_all(root.join("artist")).unwrap();
+        let delta = get_changed_data(&locations, &store.get_known_dirs().unwrap());
+        let report = store.update_incremental(&delta).unwrap();
+        assert_eq!(report.locations[0].entries.len(), 3);
+    }
+
+    #[test]
+    fn nested_paths_are_ignored() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("loc");
+        fs::create_dir_all(root.join("movie/extras/deleted")).unwrap();
+        fs::create_dir_all(root.join("movie/subs")).unwrap();
+        fs::create_dir_all(root.join("other")).unwrap();
+        fs::write(root.join("movie/movie.mkv"), "mkv").unwrap();
+        fs::write(root.join("movie/movie.nfo"), "nfo").unwrap();
+        fs::write(root.join("movie/subs/en.srt"), "srt").unwrap();
+        fs::write(root.join("movie/subs/Thumbs.db"), "db").unwrap();
+        fs::write(root.join("movie/extras/deleted/scene.mkv"), "scene").unwrap();
+        fs::write(root.join("other/next.mkv.part"), "part").unwrap();
+        fs::write(root.join("download.crdownload"), "x").unwrap();
+
+        let mut location = location(&root);
+        location.exclude_globs = "extras\n*.nfo".to_string();
+        let (entries, _) = list_files_in_dir(&location);
+        assert_eq!(entries.len(), 2);
+        let files: Vec<_> = entries[0]
+            .files
+            .iter()
+            .map(|f| f.path.strip_prefix(&root).unwrap().to_path_buf())
+            .collect();
+        assert_eq!(
+            files,
+            [
+                PathBuf::from("movie/movie.mkv"),
+                PathBuf::from("movie/subs/en.srt")
+            ]
+        );
+        assert!(entries[1].files.is_empty());
+
+        // New patterns apply to entries that did not change on disk
+        let db = tmp.path().join("test.sqlite3");
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+        store.add_location("loc", root.to_str().unwrap()).unwrap();
+        let locations = store.get_locations().unwrap();
+        let (data, reports) = get_all_data(&locations);
+        store.update(&data, &reports).unwrap();
+        let movie = store
+            .get_all_entries()
+            .iter()
+            .find(|e| e.name == "movie")
+            .cloned()
+            .unwrap();
+        assert_eq!(store.get_files(&movie).unwrap().len(), 4);
+
+        assert!(store
+            .set_location_globs(locations[0].id, &["[a-"], &[])
+            .is_err());
+        store
+            .set_location_globs(locations[0].id, &["*.mkv"], &["movie/extras"])
+            .unwrap();
+        let locations = store.get_locations().unwrap();
+        let delta = get_changed_data(&locations, &store.get_known_dirs().unwrap());
+        store.update_incremental(&delta).unwrap();
+        let files = store.get_files(&movie).unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(files[0].path.ends_with("movie/movie.mkv"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_follow_location_policy() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("loc");
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a/x.bin"), "xxx").unwrap();
+        fs::hard_link(root.join("a/x.bin"), root.join("a/hard.bin")).unwrap();
+        fs::write(root.join("b/y.bin"), "yyyyy").unwrap();
+        symlink(root.join("b"), root.join("a/link")).unwrap();
+        symlink(root.join("a"), root.join("a/loop")).unwrap();
+        symlink(root.join("a"), root.join("c")).unwrap();
+
+        let scan = |policy: SymlinkPolicy| {
+            let mut location = location(&root);
+            location.symlinks = policy as i32;
+            list_files_in_dir(&location)
+        };
+        let names = |entry: &DirEntry| -> Vec<String> {
+            entry.files.iter().map(|f| f.name.clone()).collect()
+        };
+
+        let (entries, report) = scan(SymlinkPolicy::Ignore);
+        assert!(report.is_empty());
+        assert_eq!(entries.len(), 2);
+        assert_eq!(names(&entries[0]), ["hard.bin", "x.bin"]);
+        assert_eq!(entries[0].size, 3);
+        assert!(entries[0].files[1].hard_link);
+        assert_eq!(entries[0].files[0].inode, entries[0].files[1].inode);
+
+        let (entries, _) = scan(SymlinkPolicy::Record);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(names(&entries[0]), ["hard.bin", "link", "loop", "x.bin"]);
+        assert_eq!(entries[0].size, 3);
+        assert_eq!(entries[0].files[1].symlink_target, Some(root.join("b")));
+        assert_eq!(entries[0].files[1].size, 0);
+        let c = entries.iter().find(|e| e.name == "c").unwrap();
+        assert_eq!(c.files[0].symlink_target, Some(root.join("a")));
+
+        let (entries, report) = scan(SymlinkPolicy::Follow);
+        let a = entries.iter().find(|e| e.name == "a").unwrap();
+        assert_eq!(names(a), ["hard.bin", "y.bin", "x.bin"]);
+        assert_eq!(a.size, 8);
+        assert!(report
+            .skipped
+            .iter()
+            .any(|s| s.path == root.join("a/loop") && s.reason == SkipReason::LinkLoop));
+        // Followed at the top level like any other entry
+        let c = entries.iter().find(|e| e.name == "c").unwrap();
+        assert_eq!(c.size, 8);
+
+        // Link info is stored with the files
+        let db = tmp.path().join("test.sqlite3");
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+        store.add_location("loc", root.to_str().unwrap()).unwrap();
+        let locations = store.get_locations().unwrap();
+        assert_eq!(locations[0].symlink_policy(), SymlinkPolicy::Record);
+        let (data, reports) = get_all_data(&locations);
+        store.update(&data, &reports).unwrap();
+        let a = store
+            .get_all_entries()
+            .iter()
+            .find(|e| e.name == "a")
+            .cloned()
+            .unwrap();
+        let files = store.get_files(&a).unwrap();
+        assert_eq!(a.size, 3);
+        assert!(files[0].inode.is_some());
+        assert!(files.iter().any(|f| f.hard_link));
+        let link = files.iter().find(|f| f.name == "link").unwrap();
+        assert_eq!(link.symlink_target.as_deref(), root.join("b").to_str());
+    }
+
+    #[test]
+    fn scans_report_progress_and_stop_when_cancelled() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("loc");
+        fs::create_dir_all(root.join("a/sub")).unwrap();
+        fs::write(root.join("a/x.bin"), "xxx").unwrap();
+        fs::write(root.join("a/sub/y.bin"), "yy").unwrap();
+        fs::write(root.join("b.bin"), "b").unwrap();
+
+        let db = tmp.path().join("test.sqlite3");
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+        store.add_location("loc", root.to_str().unwrap()).unwrap();
+        let locations = store.get_locations().unwrap();
+
+        let updates = Mutex::new(Vec::new());
+        let collect = |progress: &ScanProgress| updates.lock().unwrap().push(progress.clone());
+        let (data, reports) = Scanner::new().progress(&collect).get_all_data(&locations);
+        assert_eq!(data.len(), 2);
+        assert!(reports[0].is_complete());
+
+        let last = updates.into_inner().unwrap().pop().unwrap();
+        assert!(last.done);
+        assert_eq!(last.location_id, locations[0].id);
+        assert_eq!((last.dirs, last.files, last.bytes), (2, 3, 6));
+        store.update(&data, &reports).unwrap();
+
+        // A cancelled scan finds nothing and must not mark entries missing
+        let token = CancelToken::new();
+        token.cancel();
+        let (data, reports) = Scanner::new()
+            .cancel_token(token.clone())
+            .get_all_data(&locations);
+        assert!(data.is_empty());
+        assert!(reports[0].cancelled);
+        assert!(!reports[0].is_complete());
+
+        store.update(&data, &reports).unwrap();
+        assert_eq!(store.get_all_entries().len(), 2);
+        assert!(store
+            .get_all_entries()
+            .iter()
+            .all(|e| e.missing_since.is_none()));
+    }
+
+    #[test]
+    fn locations_on_one_device_are_grouped() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut locations = Vec::new();
+        for (id, name) in [(1, "one"), (2, "two"), (3, "gone")] {
+            let root = tmp.path().join(name);
+            if name != "gone" {
+                fs::create_dir_all(root.join("entry")).unwrap();
+                fs::write(root.join("entry/x.bin"), name).unwrap();
+            }
+            let mut location = location(&root);
+            location.id = id;
+            locations.push(location);
+        }
+
+        let ids = |scanner: &Scanner| -> Vec<Vec<i32>> {
+            let groups = scanner.groups(&locations);
+            groups
+                .iter()
+                .map(|group| group.iter().map(|l| l.id).collect())
+                .collect()
+        };
+
+        let grouped = Scanner::new();
+        if cfg!(unix) {
+            assert_eq!(ids(&grouped), [vec![1, 2], vec![3]]);
+        }
+        let separate = Scanner::new().config(ScanConfig {
+            threads: 1,
+            group_by_device: false,
+            sniff_mime: false,
+        });
+        assert_eq!(ids(&separate), [vec![1], vec![2], vec![3]]);
+
+        let (data, reports) = separate.get_all_data(&locations);
+        assert_eq!(data.len(), 2);
+        let ids: Vec<i32> = reports.iter().map(|r| r.location_id).collect();
+        assert_eq!(ids, [1, 2, 3]);
+        assert!(reports[2].offline);
+    }
+
+    #[test]
+    fn groups_are_scanned_in_parallel() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut locations = Vec::new();
+        for id in 1..=2 {
+            let root = tmp.path().join(id.to_string());
+            fs::create_dir_all(root.join("entry")).unwrap();
+            fs::write(root.join("entry/x.bin"), "x").unwrap();
+            let mut location = location(&root);
+            location.id = id;
+            locations.push(location);
+        }
+
+        // Each scan waits in its first progress update until the other one has started
+        let started = Mutex::new(HashSet::new());
+        let both_started = Condvar::new();
+        let wait_for_other = |progress: &ScanProgress| {
+            let mut started = started.lock().unwrap();
+            if started.insert(progress.location_id) {
+                both_started.notify_all();
+                let _ = both_started
+                    .wait_timeout_while(started, Duration::from_secs(5), |s| s.len() < 2)
+                    .unwrap();
+            }
+        };
+
+        let scanner = Scanner::new()
+            .config(ScanConfig {
+                threads: 2,
+                group_by_device: false,
+                sniff_mime: false,
+            })
+            .progress(&wait_for_other);
+
+        let start = Instant::now();
+        let (data, _) = scanner.get_all_data(&locations);
+        assert_eq!(data.len(), 2);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn streamed_scans_match_collected_scans() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("loc");
+        for ix in 0..10 {
+            fs::create_dir_all(root.join(format!("{}/sub", ix))).unwrap();
+            fs::write(root.join(format!("{}/sub/x.bin", ix)), "x".repeat(ix)).unwrap();
+        }
+
+        let db = tmp.path().join("test.sqlite3");
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+        store.add_location("loc", root.to_str().unwrap()).unwrap();
+        let locations = store.get_locations().unwrap();
+
+        let (sender, receiver) = mpsc::sync_channel(2);
+        let report = thread::scope(|s| {
+            s.spawn(|| Scanner::new().send_all_data(&locations, sender));
+            store.update_streaming(receiver, 3)
+        })
+        .unwrap();
+        assert_eq!(report.location(locations[0].id).unwrap().entries.len(), 10);
+        assert_eq!(store.get_locations().unwrap()[0].size, 45);
+        assert!(store.get_locations().unwrap()[0].last_scan.is_some());
+
+        let (data, reports) = get_all_data(&locations);
+        assert!(store.update(&data, &reports).unwrap().is_empty());
+
+        // A dropped receiver stops the scan but leaves the token usable
+        let token = CancelToken::new();
+        let scanner = Scanner::new().cancel_token(token.clone());
+        let (sender, receiver) = mpsc::sync_channel(0);
+        drop(receiver);
+        scanner.send_all_data(&locations, sender);
+        assert!(!token.is_cancelled());
+        assert_eq!(scanner.get_all_data(&locations).0.len(), 10);
+
+        let child = token.child();
+        token.cancel();
+        assert!(child.is_cancelled());
+    }
 }
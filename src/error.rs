@@ -0,0 +1,44 @@
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::query::QueryError;
+
+pub type Result<T> = std::result::Result<T, OrganizerError>;
+
+#[derive(Debug, Error)]
+pub enum OrganizerError {
+    #[error("Failed to connect to database: {0}")]
+    Connection(#[from] diesel::ConnectionError),
+
+    #[error("Database error: {0}")]
+    Database(#[from] diesel::result::Error),
+
+    #[error("Failed to run migrations: {0}")]
+    Migration(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("Filesystem error on '{}': {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Invalid search query: {0}")]
+    Query(#[from] QueryError),
+}
+
+impl OrganizerError {
+    pub fn io(path: impl AsRef<Path>, source: std::io::Error) -> Self {
+        OrganizerError::Io {
+            path: path.as_ref().to_path_buf(),
+            source,
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use log::{debug, info, warn};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Instant;
+
+/// Bytes read from the start of each file before deciding if it needs a full hash
+const PARTIAL_SIZE: u64 = 16 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct HashCandidate {
+    pub file_id: i32,
+    pub path: PathBuf,
+    pub size: u64,
+    /// Full hash from an earlier run, the file is not read again if set
+    pub hash: Option<String>,
+}
+
+/// Computes full content hashes for all candidates that might be duplicates. Files are first
+/// grouped by size, then by a hash of their first bytes and only files that still share a group
+/// are read in full. Returns the new hashes by file id.
+pub fn hash_candidates(candidates: Vec<HashCandidate>, threads: usize) -> Vec<(i32, String)> {
+    let start = Instant::now();
+
+    let mut by_size: HashMap<u64, Vec<HashCandidate>> = HashMap::new();
+    for candidate in candidates.into_iter().filter(|c| c.size > 0) {
+        by_size.entry(candidate.size).or_default().push(candidate);
+    }
+
+    let same_size: Vec<HashCandidate> = by_size
+        .into_values()
+        .filter(|group| group.len() > 1 && group.iter().any(|c| c.hash.is_none()))
+        .flatten()
+        .collect();
+
+    debug!("Partial hashing {} files", same_size.len());
+
+    let partials = par_map(&same_size, threads, |c| {
+        if c.size <= PARTIAL_SIZE {
+            // Small files are read in full right away
+            return c.hash.clone().or_else(|| hash_file(&c.path, None));
+        }
+        hash_file(&c.path, Some(PARTIAL_SIZE))
+    });
+
+    let mut by_partial: HashMap<(u64, String), Vec<&HashCandidate>> = HashMap::new();
+    let mut hashes = Vec::new();
+    for (candidate, partial) in same_size.iter().zip(partials) {
+        let partial = match partial {
+            Some(partial) => partial,
+            None => continue,
+        };
+
+        if candidate.size <= PARTIAL_SIZE {
+            if candidate.hash.is_none() {
+                hashes.push((candidate.file_id, partial));
+            }
+        } else {
+            by_partial
+                .entry((candidate.size, partial))
+                .or_default()
+                .push(candidate);
+        }
+    }
+
+    let needs_full: Vec<&HashCandidate> = by_partial
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .flatten()
+        .filter(|c| c.hash.is_none())
+        .collect();
+
+    debug!("Full hashing {} files", needs_full.len());
+
+    let full = par_map(&needs_full, threads, |c| hash_file(&c.path, None));
+    for (candidate, hash) in needs_full.iter().zip(full) {
+        if let Some(hash) = hash {
+            hashes.push((candidate.file_id, hash));
+        }
+    }
+
+    info!(
+        "Hashed {} files took: {:?} ms",
+        hashes.len(),
+        start.elapsed().as_millis()
+    );
+
+    hashes
+}
+
+/// Full content hash of a single file, comparable with the hashes from `hash_candidates`.
+pub fn content_hash(path: &Path) -> Option<String> {
+    hash_file(path, None)
+}
+
+fn hash_file(path: &Path, limit: Option<u64>) -> Option<String> {
+    let res = fs::File::open(path).and_then(|file| {
+        let mut hasher = blake3::Hasher::new();
+        match limit {
+            Some(limit) => io::copy(&mut file.take(limit), &mut hasher)?,
+            None => io::copy(&mut io::BufReader::new(file), &mut hasher)?,
+        };
+        Ok(hasher.finalize().to_hex().to_string())
+    });
+
+    match res {
+        Ok(hash) => Some(hash),
+        Err(err) => {
+            warn!("Failed to hash {:?}: {}", path, err);
+            None
+        }
+    }
+}
+
+/// Maps `items` on up to `threads` threads, keeping the order.
+fn par_map<T, R, F>(items: &[T], threads: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    if items.is_empty() {
+        return Vec::new();
+    }
+
+    let chunk_size = items.len().div_ceil(threads.max(1));
+    let f = &f;
+
+    thread::scope(|s| {
+        let children: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| s.spawn(move || chunk.iter().map(f).collect::<Vec<R>>()))
+            .collect();
+
+        children
+            .into_iter()
+            .flat_map(|c| c.join().expect("Failed to join thread!"))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(file_id: i32, path: PathBuf) -> HashCandidate {
+        let size = fs::metadata(&path).unwrap().len();
+        HashCandidate {
+            file_id,
+            path,
+            size,
+            hash: None,
+        }
+    }
+
+    #[test]
+    fn only_possible_duplicates_are_hashed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let big = vec![7u8; PARTIAL_SIZE as usize * 2];
+        let mut big_other = big.clone();
+        *big_other.last_mut().unwrap() = 8;
+
+        fs::write(tmp.path().join("a"), &big).unwrap();
+        fs::write(tmp.path().join("b"), &big).unwrap();
+        fs::write(tmp.path().join("c"), &big_other).unwrap();
+        fs::write(tmp.path().join("d"), "unique size").unwrap();
+        fs::write(tmp.path().join("e"), "12").unwrap();
+        fs::write(tmp.path().join("f"), "12").unwrap();
+
+        let candidates = ["a", "b", "c", "d", "e", "f"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| candidate(i as i32, tmp.path().join(name)))
+            .collect();
+
+        let mut hashes = hash_candidates(candidates, 2);
+        hashes.sort();
+
+        let ids: Vec<i32> = hashes.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![0, 1, 2, 4, 5]);
+        assert_eq!(hashes[0].1, hashes[1].1);
+        assert_ne!(hashes[0].1, hashes[2].1);
+        assert_eq!(hashes[3].1, hashes[4].1);
+    }
+}
@@ -1,18 +1,15 @@
 #![allow(unused_imports)]
-use anyhow::Context;
 // mod filter;
 // extern crate intmap;
-use anyhow::{bail, Result};
 use log::{debug, error, info, trace, warn};
 
 use regex::{escape, Regex, RegexBuilder};
-use time::Instant;
+use std::time::Instant;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs::create_dir_all;
 use std::fs::rename;
 use std::path::Path;
-use std::usize;
 //use std::mem;
 //use std::cmp::Ordering;
 
@@ -20,8 +17,16 @@ use std::fs;
 use std::fs::metadata;
 
 //use intmap::IntMap;
-use crate::models::{DirEntry, Entry, File, LabelAutoFilter, Location};
+use crate::error::{OrganizerError, Result};
+use crate::models::{
+    DirEntry, DuplicateGroup, Entry, File, LabelAutoFilter, Location, ScanDelta, ScanEvent,
+    ScanReport, SimilarGroup, Statistics, SymlinkPolicy, UpdateReport,
+};
+use crate::query::{self, Query, QueryTarget};
+use crate::similar::find_similar_entries;
 use crate::store::Store;
+#[cfg(all(feature = "watch", target_os = "linux"))]
+use crate::{dir_search::get_changed_data, watcher::Watcher};
 
 #[derive(Debug, Copy, Clone)]
 pub enum LabelState {
@@ -37,6 +42,29 @@ pub struct Label {
     pub state: LabelState,
 }
 
+fn label_filter(
+    include_labels: &HashSet<i32>,
+    exlude_labels: &HashSet<i32>,
+    source: &Store,
+    entry_id: i32,
+) -> bool {
+    if exlude_labels.is_empty() && include_labels.is_empty() {
+        return true;
+    }
+
+    if let Some(entry_labels) = source.entry_labels(entry_id) {
+        if exlude_labels.iter().any(|lbl| entry_labels.contains(lbl)) {
+            return false;
+        }
+
+        if include_labels.iter().any(|lbl| entry_labels.contains(lbl)) {
+            return true;
+        }
+    }
+
+    include_labels.is_empty()
+}
+
 pub fn create_match_regex(needle: &str) -> Regex {
     let mut res: String = String::new();
 
@@ -55,17 +83,128 @@ const GB: u64 = KB * KB * KB;
 
 pub fn pretty_size(size: u64) -> String {
     match size {
-        x if x > GB => String::from((size / GB).to_string() + " GB"),
-        x if x > MB => String::from((size / MB).to_string() + " MB"),
-        x if x > KB => String::from((size / KB).to_string() + " KB"),
-        _ => String::from(size.to_string() + " B"),
+        x if x > GB => (size / GB).to_string() + " GB",
+        x if x > MB => (size / MB).to_string() + " MB",
+        x if x > KB => (size / KB).to_string() + " KB",
+        _ => size.to_string() + " B",
     }
 }
 
 #[derive(Debug)]
 struct Search {
     string: String,
-    regex: Regex,
+    query: Query,
+    /// Also match the query against the files of each entry
+    in_files: bool,
+    /// Indexes into the entry files that matched, by entry id
+    file_matches: HashMap<i32, Vec<usize>>,
+}
+
+/// Entry as seen by the search query, labels and files are looked up in the store caches.
+/// With `file` set name, path, size and extension are taken from that file instead.
+struct EntryTarget<'a> {
+    entry: &'a Entry,
+    file: Option<&'a File>,
+    source: &'a Store,
+    locations: &'a [Location],
+}
+
+impl QueryTarget for EntryTarget<'_> {
+    fn name(&self) -> &str {
+        match self.file {
+            Some(file) => &file.name,
+            None => &self.entry.name,
+        }
+    }
+
+    fn path(&self) -> &str {
+        match self.file {
+            Some(file) => &file.path,
+            None => &self.entry.path,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match self.file {
+            Some(file) => file.size as u64,
+            None => self.entry.size as u64,
+        }
+    }
+
+    fn grade(&self) -> Option<i32> {
+        self.entry.grade
+    }
+
+    fn created(&self) -> Option<i64> {
+        match self.file {
+            Some(file) => file.created,
+            None => self.entry.created,
+        }
+    }
+
+    fn modified(&self) -> Option<i64> {
+        match self.file {
+            Some(file) => file.modified,
+            None => self.entry.modified,
+        }
+    }
+
+    fn has_label(&self, label: &str) -> bool {
+        let entry_labels = match self.source.entry_labels(self.entry.id) {
+            Some(entry_labels) => entry_labels,
+            None => return false,
+        };
+
+        self.source
+            .get_all_labels()
+            .iter()
+            .any(|lbl| entry_labels.contains(&lbl.id) && lbl.name.to_lowercase() == label)
+    }
+
+    fn in_location(&self, location: &str) -> bool {
+        self.locations
+            .iter()
+            .find(|loc| loc.id == self.entry.location_id)
+            .is_some_and(|loc| loc.name.to_lowercase().contains(location))
+    }
+
+    fn has_extension(&self, ext: &str) -> bool {
+        let matches = |name: &str| {
+            Path::new(name)
+                .extension()
+                .is_some_and(|e| e.to_string_lossy().to_lowercase() == ext)
+        };
+
+        if let Some(file) = self.file {
+            return matches(&file.name);
+        }
+
+        matches(&self.entry.name)
+            || self
+                .source
+                .get_files(self.entry)
+                .is_some_and(|files| files.iter().any(|f| matches(&f.name)))
+    }
+
+    fn has_type(&self, mime: &str) -> bool {
+        if let Some(file) = self.file {
+            return file.has_type(mime);
+        }
+
+        self.source
+            .get_files(self.entry)
+            .is_some_and(|files| files.iter().any(|f| f.has_type(mime)))
+    }
+
+    fn has_only_type(&self, mime: &str) -> bool {
+        if let Some(file) = self.file {
+            return file.has_type(mime);
+        }
+
+        self.source
+            .get_files(self.entry)
+            .is_some_and(|files| !files.is_empty() && files.iter().all(|f| f.has_type(mime)))
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -97,6 +236,15 @@ pub enum SortOrder {
     Desc = 1,
 }
 
+/// Which of the entries that vanished from disk, but are still kept by the store, to list.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[repr(u32)]
+pub enum MissingFilter {
+    Hide = 0,
+    Show = 1,
+    Only = 2,
+}
+
 // ************** Constant HWNDS **************
 
 pub struct Lens {
@@ -108,23 +256,32 @@ pub struct Lens {
     /// Used for application using Lens
     label_states: Vec<Label>,
 
+    /// Cached for searching on location name
+    locations: Vec<Location>,
+
+    missing_filter: MissingFilter,
     search: Search,
     sort: Sort,
 }
 
 impl Lens {
-    pub fn new(db_path: &str) -> Self {
+    pub fn new(db_path: &str) -> Result<Self> {
         let search = Search {
             string: String::new(),
-            regex: Regex::new(".*").unwrap(),
+            query: Query::All,
+            in_files: false,
+            file_matches: HashMap::new(),
         };
 
-        let mut source = Store::init(db_path);
-        source.load_from_store();
+        let mut source = Store::init(db_path)?;
+        source.load_from_store()?;
+        let locations = source.get_locations()?;
 
         let mut lens = Lens {
             source,
             ix_list: Vec::new(),
+            locations,
+            missing_filter: MissingFilter::Hide,
             search,
             sort: Sort::new(SortColumn::Name, SortOrder::Asc),
 
@@ -135,34 +292,146 @@ impl Lens {
         };
         lens.update_ix_list();
 
-        lens
+        Ok(lens)
     }
 
-    pub fn update_data(&mut self, data: &mut Vec<(i32, DirEntry)>) {
+    /// Applies a full scan from `dir_search::get_all_data`, the scan reports tell which
+    /// locations were offline.
+    pub fn update_data(
+        &mut self,
+        data: &mut [(i32, DirEntry)],
+        reports: &[ScanReport],
+    ) -> Result<UpdateReport> {
         let start = Instant::now();
         trace!("Starting data update");
 
         self.ix_list.clear();
-        self.source.update(data);
+        let report = self.source.update(data, reports)?;
+        self.locations = self.source.get_locations()?;
 
-        trace!(
-            "Data updated, {:?} ms",
-            start.elapsed().whole_milliseconds()
-        );
+        trace!("Data updated, {:?} ms", start.elapsed().as_millis());
 
         self.update_ix_list();
+
+        Ok(report)
+    }
+
+    /// Applies a scan from `dir_search::Scanner::send_all_data` while it runs, see
+    /// `Store::update_streaming`.
+    pub fn update_streaming(
+        &mut self,
+        events: impl IntoIterator<Item = ScanEvent>,
+        batch_size: usize,
+    ) -> Result<UpdateReport> {
+        let start = Instant::now();
+        trace!("Starting streaming data update");
+
+        self.ix_list.clear();
+        let report = self.source.update_streaming(events, batch_size)?;
+        self.locations = self.source.get_locations()?;
+
+        trace!("Data updated, {:?} ms", start.elapsed().as_millis());
+
+        self.update_ix_list();
+
+        Ok(report)
+    }
+
+    pub fn update_changed_data(&mut self, delta: &ScanDelta) -> Result<UpdateReport> {
+        let start = Instant::now();
+        trace!("Starting incremental data update");
+
+        self.ix_list.clear();
+        let report = self.source.update_incremental(delta)?;
+        self.locations = self.source.get_locations()?;
+
+        trace!("Data updated, {:?} ms", start.elapsed().as_millis());
+
+        self.update_ix_list();
+
+        Ok(report)
+    }
+
+    /// Creates a watcher for all locations, feed it to `apply_watch_events` to keep the lens
+    /// up to date.
+    #[cfg(all(feature = "watch", target_os = "linux"))]
+    pub fn watch_locations(&self) -> Result<Watcher> {
+        Watcher::new(&self.get_locations()?)
+    }
+
+    /// Applies pending filesystem events, returns the number of changed or removed entries.
+    #[cfg(all(feature = "watch", target_os = "linux"))]
+    pub fn apply_watch_events(&mut self, watcher: &mut Watcher) -> Result<usize> {
+        let mut delta = watcher.poll()?;
+
+        if watcher.take_overflowed() {
+            // Events were lost, fall back to comparing directory mtimes
+            delta = get_changed_data(&self.locations, &self.source.get_known_dirs()?);
+        }
+
+        let count = delta.changed.len() + delta.removed.len();
+        if count > 0 {
+            self.update_changed_data(&delta)?;
+        }
+
+        Ok(count)
     }
 
     pub fn update_ix_list(&mut self) {
         let start = Instant::now();
 
         self.ix_list.clear();
+        self.search.file_matches.clear();
 
         {
-            let search = &self.search;
+            let search = &mut self.search;
+            let match_files = search.in_files && search.query != Query::All;
 
             for (i, e) in self.source.get_all_entries().iter().enumerate() {
-                if search.regex.is_match(&e.name) && self.label_filter(e.id) {
+                let listed = match self.missing_filter {
+                    MissingFilter::Hide => e.missing_since.is_none(),
+                    MissingFilter::Show => true,
+                    MissingFilter::Only => e.missing_since.is_some(),
+                };
+
+                if !listed
+                    || !label_filter(
+                        &self.include_labels,
+                        &self.exlude_labels,
+                        &self.source,
+                        e.id,
+                    )
+                {
+                    continue;
+                }
+
+                let mut target = EntryTarget {
+                    entry: e,
+                    file: None,
+                    source: &self.source,
+                    locations: &self.locations,
+                };
+                let mut is_match = search.query.matches(&target);
+
+                if match_files {
+                    let files = self.source.get_files(e).map_or(&[][..], |f| f.as_slice());
+                    let matched: Vec<usize> = files
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, file)| {
+                            target.file = Some(file);
+                            search.query.matches(&target)
+                        })
+                        .map(|(ix, _)| ix)
+                        .collect();
+
+                    if !matched.is_empty() {
+                        is_match = true;
+                        search.file_matches.insert(e.id, matched);
+                    }
+                }
+
+                if is_match {
                     self.ix_list.push(i);
                 }
             }
@@ -173,43 +442,13 @@ impl Lens {
         info!(
             "ix_list update with {:?} entries took: {:?} ms",
             self.ix_list.len(),
-            start.elapsed().whole_milliseconds()
+            start.elapsed().as_millis()
         );
 
         trace!("ix_list include: {:?}  ", self.include_labels);
         trace!("ix_list exclude: {:?}  ", self.exlude_labels);
     }
 
-    fn label_filter(&self, entry_id: i32) -> bool {
-        if self.exlude_labels.is_empty() && self.include_labels.is_empty() {
-            return true;
-        }
-
-        if let Some(entry_labels) = self.source.entry_labels(entry_id) {
-            if self
-                .exlude_labels
-                .iter()
-                .any(|lbl| entry_labels.contains(lbl))
-            {
-                return false;
-            }
-
-            if self
-                .include_labels
-                .iter()
-                .any(|lbl| entry_labels.contains(lbl))
-            {
-                return true;
-            }
-        }
-
-        if self.include_labels.is_empty() {
-            return true;
-        } else {
-            return false;
-        }
-    }
-
     pub fn order_by(&mut self, column: SortColumn, order: SortOrder) {
         self.sort = Sort::new(column, order);
         self.sort();
@@ -219,7 +458,7 @@ impl Lens {
         let column = &self.sort.column;
         let order = &self.sort.order;
 
-        println!("Sort by {:?} {:?}", column, order);
+        debug!("Sort by {:?} {:?}", column, order);
 
         let entries: &Vec<Entry> = self.source.entriesCache.as_ref();
 
@@ -228,7 +467,7 @@ impl Lens {
             let b = &entries[bx];
 
             match column {
-                SortColumn::Date => a.name.cmp(&b.name),
+                SortColumn::Date => a.modified.cmp(&b.modified),
                 SortColumn::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
                 SortColumn::Path => a.path.cmp(&b.path),
                 SortColumn::Size => a.size.cmp(&b.size),
@@ -246,16 +485,46 @@ impl Lens {
         });
     }
 
-    pub fn update_search_text(&mut self, new_string: &str) -> Option<usize> {
+    /// Filters the entries with a search query, see `query` for the syntax. Returns the new
+    /// entry count if the search changed. An invalid query keeps the previous search.
+    pub fn update_search_text(&mut self, new_string: &str) -> Result<Option<usize>> {
         if new_string != self.search.string {
-            self.search.regex = create_match_regex(new_string);
+            self.search.query = query::parse(new_string)?;
             self.search.string = String::from(new_string);
 
             self.update_ix_list();
-            return Some(self.ix_list.len());
+            return Ok(Some(self.ix_list.len()));
         }
 
-        None
+        Ok(None)
+    }
+
+    /// Missing entries are hidden unless set otherwise, returns the new entry count.
+    pub fn set_missing_filter(&mut self, filter: MissingFilter) -> usize {
+        if filter != self.missing_filter {
+            self.missing_filter = filter;
+            self.update_ix_list();
+        }
+
+        self.ix_list.len()
+    }
+
+    /// Also search the file names of each entry, entries with a matching file are included.
+    pub fn set_search_in_files(&mut self, in_files: bool) -> usize {
+        if in_files != self.search.in_files {
+            self.search.in_files = in_files;
+            self.update_ix_list();
+        }
+
+        self.ix_list.len()
+    }
+
+    /// Indexes into `get_dir_files(ix)` of the files that matched the search, empty if only the
+    /// entry itself matched or searching in files is off.
+    pub fn get_matching_files(&self, ix: usize) -> &[usize] {
+        self.get_dir_entry(ix)
+            .and_then(|entry| self.search.file_matches.get(&entry.id))
+            .map_or(&[], |matched| matched.as_slice())
     }
 
     // *** Entries ***
@@ -285,7 +554,7 @@ impl Lens {
             .entriesCache
             .binary_search_by_key(&entry_id, |e| e.id);
         if let Ok(ix) = ix {
-            return self.source.entriesCache.get(ix);
+            self.source.entriesCache.get(ix)
         } else {
             None
         }
@@ -300,15 +569,11 @@ impl Lens {
     }
 
     pub fn get_file_count(&self, ix: usize) -> Option<usize> {
-        if let Some(ref files) = self.get_dir_files(ix) {
-            Some(files.len())
-        } else {
-            None
-        }
+        self.get_dir_files(ix).map(|files| files.len())
     }
 
     pub fn get_file_entry(&self, dir_ix: usize, file_ix: usize) -> Option<&File> {
-        if let Some(ref files) = self.get_dir_files(dir_ix) {
+        if let Some(files) = self.get_dir_files(dir_ix) {
             return files.get(file_ix);
         }
         None
@@ -343,17 +608,19 @@ impl Lens {
         self.update_label_states();
     }
 
-    pub fn add_label(&mut self, name: &str) {
-        self.source.add_label(name);
+    pub fn add_label(&mut self, name: &str) -> Result<()> {
+        self.source.add_label(name)?;
 
         self.update_label_states();
+        Ok(())
     }
 
-    pub fn remove_label(&mut self, label_id: u32) {
-        self.source.remove_label(label_id as i32);
+    pub fn remove_label(&mut self, label_id: u32) -> Result<()> {
+        self.source.remove_label(label_id as i32)?;
         self.remove_label_filter(label_id);
 
         self.update_label_states();
+        Ok(())
     }
 
     pub fn update_label_states(&mut self) {
@@ -370,7 +637,7 @@ impl Lens {
             };
 
             self.label_states.push(Label {
-                id: lbl.id.clone(),
+                id: lbl.id,
                 name: lbl.name.clone(),
                 state: lbl_state,
             });
@@ -385,179 +652,262 @@ impl Lens {
         self.source.dir_labels(id as i32)
     }
 
-    pub fn add_entry_labels(&mut self, entries: Vec<u32>, labels: Vec<u32>) {
+    pub fn add_entry_labels(&mut self, entries: Vec<u32>, labels: Vec<u32>) -> Result<()> {
         let start = Instant::now();
         let count = entries.len();
         self.source.add_entry_labels(
             entries.into_iter().map(|e| e as i32).collect(),
             labels.into_iter().map(|e| e as i32).collect(),
-        );
+        )?;
 
         trace!(
             "set_entry_labels update with {:?} entries took: {:?} ms",
             count,
-            start.elapsed().whole_milliseconds()
+            start.elapsed().as_millis()
         );
 
         self.update_ix_list();
+        Ok(())
     }
 
-    pub fn remove_entry_labels(&mut self, entries: Vec<u32>, labels: Vec<u32>) {
+    pub fn remove_entry_labels(&mut self, entries: Vec<u32>, labels: Vec<u32>) -> Result<()> {
         let start = Instant::now();
         let count = entries.len();
         self.source.remove_entry_labels(
             entries.into_iter().map(|e| e as i32).collect(),
             labels.into_iter().map(|e| e as i32).collect(),
-        );
+        )?;
 
         trace!(
             "set_entry_labels update with {:?} entries took: {:?} ms",
             count,
-            start.elapsed().whole_milliseconds()
+            start.elapsed().as_millis()
         );
 
         self.update_ix_list();
+        Ok(())
     }
 
     /*** Locations ***/
-    pub fn add_location(&mut self, name: &str, path: &str) {
-        self.source.add_location(name, path);
+    pub fn add_location(&mut self, name: &str, path: &str) -> Result<()> {
+        self.source.add_location(name, path)?;
+        self.locations = self.source.get_locations()?;
+        Ok(())
     }
 
-    pub fn remove_location(&mut self, id: u32) {
-        self.source.remove_location(id as i32);
+    pub fn remove_location(&mut self, id: u32) -> Result<()> {
+        self.remove_location_id(id as i32)
     }
 
-    pub fn remove_location_id(&mut self, id: i32) {
-        self.source.remove_location(id);
+    pub fn remove_location_id(&mut self, id: i32) -> Result<()> {
+        self.source.remove_location(id)?;
+        self.locations = self.source.get_locations()?;
+        Ok(())
     }
 
-    pub fn get_locations(&self) -> Vec<Location> {
+    pub fn get_locations(&self) -> Result<Vec<Location>> {
         self.source.get_locations()
     }
 
+    /// Takes effect on the next scan, see `Store::set_location_globs`.
+    pub fn set_location_globs(
+        &mut self,
+        id: i32,
+        include: &[&str],
+        exclude: &[&str],
+    ) -> Result<()> {
+        self.source.set_location_globs(id, include, exclude)?;
+        self.locations = self.source.get_locations()?;
+        Ok(())
+    }
+
+    /// Takes effect on the next scan.
+    pub fn set_symlink_policy(&mut self, id: i32, policy: SymlinkPolicy) -> Result<()> {
+        self.source.set_symlink_policy(id, policy)?;
+        self.locations = self.source.get_locations()?;
+        Ok(())
+    }
+
+    /// Takes effect on the next scan, see `Store::set_entry_layout`.
+    pub fn set_entry_layout(
+        &mut self,
+        id: i32,
+        entry_depth: i32,
+        file_entries: bool,
+    ) -> Result<()> {
+        self.source
+            .set_entry_layout(id, entry_depth, file_entries)?;
+        self.locations = self.source.get_locations()?;
+        Ok(())
+    }
+
+    /// Entry count, size and average grade per location and label, regardless of the current
+    /// search and filters.
+    pub fn get_statistics(&self) -> Result<Statistics> {
+        self.source.get_statistics()
+    }
+
+    /*** Duplicates ***/
+
+    /// Hashes files that might be duplicates using all available cores.
+    pub fn hash_files(&mut self) -> Result<usize> {
+        let threads = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+
+        self.source.hash_files(threads)
+    }
+
+    /// Duplicate files found by the last `hash_files`, largest reclaimable size first.
+    pub fn get_duplicate_files(&self) -> Vec<DuplicateGroup> {
+        self.source.find_duplicates()
+    }
+
+    /// Total bytes that can be freed by keeping a single copy of every duplicate.
+    pub fn get_reclaimable_size(&self) -> u64 {
+        self.source
+            .find_duplicates()
+            .iter()
+            .map(|group| group.reclaimable())
+            .sum()
+    }
+
+    /// Entries that look like the same release by name and size, see
+    /// `similar::find_similar_entries`. Remove the unwanted ones with `remove_entry`.
+    pub fn find_similar_entries(&self, size_tolerance: f64) -> Vec<SimilarGroup> {
+        let entries: Vec<Entry> = self
+            .source
+            .get_all_entries()
+            .iter()
+            .filter(|e| e.missing_since.is_none())
+            .cloned()
+            .collect();
+
+        find_similar_entries(&entries, size_tolerance)
+    }
+
     /*** Entry Operations ***/
     pub fn rename_entry(&mut self, entry: Entry, new_name: &str) -> Result<()> {
-        if metadata(new_name).is_ok() {
+        let path = entry.fs_path();
+        let new_path = path.with_file_name(new_name);
+
+        if metadata(&new_path).is_ok() {
             // Path already exists
-            bail!("This name already exists: '{:?}'", entry.path);
+            return Err(OrganizerError::InvalidInput(format!(
+                "This name already exists: '{:?}'",
+                new_path
+            )));
         }
 
-        let old_meta = metadata(&entry.path);
+        let old_meta = metadata(&path).map_err(|err| OrganizerError::io(&path, err))?;
 
-        if old_meta.is_err() {
-            bail!("Could not find path: '{:?}'", entry.path);
-        }
-
-        let path = Path::new(&entry.path);
-        let new_path = path.with_file_name(new_name);
+        rename(&path, &new_path).map_err(|err| OrganizerError::io(&path, err))?;
 
-        rename(&entry.path, &new_path).context("Failed to rename file")?;
+        self.source
+            .rename_entry(entry, new_name, &new_path, old_meta.is_file())?;
 
-        self.source.rename_entry(
-            entry,
-            &new_name,
-            &new_path.to_string_lossy(),
-            old_meta?.is_file(),
-        );
-
-        self.source.load_from_store();
         self.update_ix_list();
 
         Ok(())
     }
 
-    pub fn set_grade(&mut self, entry: Entry, grade: i32) {
-        self.source.set_grade(entry, grade);
+    pub fn set_grade(&mut self, entry: Entry, grade: i32) -> Result<()> {
+        self.source.set_grade(entry, grade)
     }
 
     /// Moves a entry that is a file to be a directory with the same name
     pub fn move_file_entry_to_dir_entry(&mut self, entry: &Entry) -> Result<()> {
-        let old_meta = metadata(&entry.path);
-
-        if old_meta.is_err() {
-            bail!("Did not find path '{:?}'", entry.path);
+        let path = entry.fs_path();
+        let old_meta = metadata(&path).map_err(|err| OrganizerError::io(&path, err))?;
+
+        if old_meta.is_dir() {
+            return Err(OrganizerError::InvalidInput(format!(
+                "Path is not a file: '{:?}' ",
+                entry.path
+            )));
         }
 
-        if old_meta?.is_dir() {
-            bail!("Path is not a file: '{:?}' ", entry.path);
-        }
-
-        let path = entry.path.clone();
-        let path = Path::new(&path);
-        let file_name = path.file_name().unwrap();
-        let file_stem = path.file_stem().unwrap();
+        let bad_path = || OrganizerError::InvalidInput(format!("Bad file path: '{:?}'", path));
+        let file_name = path.file_name().ok_or_else(bad_path)?;
+        let file_stem = path.file_stem().ok_or_else(bad_path)?;
 
-        let mut new_path = path.parent().unwrap().to_path_buf();
+        let mut new_path = path.parent().ok_or_else(bad_path)?.to_path_buf();
         new_path.push(file_stem);
 
-        create_dir_all(&new_path).context("Failed to create dirs!")?;
+        create_dir_all(&new_path).map_err(|err| OrganizerError::io(&new_path, err))?;
 
         new_path.push(file_name);
 
-        rename(&path, &new_path).context("Failed to rename file")?;
+        rename(&path, &new_path).map_err(|err| OrganizerError::io(&path, err))?;
 
-        self.source.move_file_to_dir(
-            entry,
-            &file_stem.to_string_lossy(),
-            &new_path.to_string_lossy(),
-        );
+        self.source
+            .move_file_to_dir(entry, &file_stem.to_string_lossy(), &new_path)?;
 
-        self.source.load_from_store();
         self.update_ix_list();
 
         Ok(())
     }
 
+    /// Deletes the entry from disk and the store. A missing entry is only removed from the
+    /// store.
     pub fn remove_entry(&mut self, entry: &Entry) -> Result<()> {
-        let meta = metadata(&entry.path)?;
+        let path = entry.fs_path();
+        let meta = if entry.missing_since.is_some() {
+            None
+        } else {
+            match metadata(&path) {
+                Ok(meta) => Some(meta),
+                // Gone since the last scan
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+                Err(err) => return Err(OrganizerError::io(&path, err)),
+            }
+        };
 
-        if meta.is_file() {
-            if let Err(err) = fs::remove_file(&entry.path) {
-                println!("Failed to delete entry: '{}' error: '{}'", entry.name, err);
-                bail!(err);
+        if meta.as_ref().is_some_and(|meta| meta.is_file()) {
+            if let Err(err) = fs::remove_file(&path) {
+                error!("Failed to delete entry: '{}' error: '{}'", entry.name, err);
+                return Err(OrganizerError::io(&path, err));
             }
         }
 
-        if meta.is_dir() {
-            if let Err(err) = fs::remove_dir_all(&entry.path) {
-                println!("Failed to delete entry: '{}' error: '{}'", entry.name, err);
-                bail!(err);
+        if meta.as_ref().is_some_and(|meta| meta.is_dir()) {
+            if let Err(err) = fs::remove_dir_all(&path) {
+                error!("Failed to delete entry: '{}' error: '{}'", entry.name, err);
+                return Err(OrganizerError::io(&path, err));
             }
         }
 
-        self.source.remove_entry(entry.id);
+        self.source.remove_entry(entry.id)?;
 
-        self.source.load_from_store();
         self.update_ix_list();
 
         Ok(())
     }
 
     pub fn remove_file(&mut self, file: &File) -> Result<()> {
-        if let Err(err) = fs::remove_file(&file.path) {
-            println!("Failed to delete file: '{}' error: '{}'", file.name, err);
-            bail!(err);
+        let path = file.fs_path();
+        if let Err(err) = fs::remove_file(&path) {
+            error!("Failed to delete file: '{}' error: '{}'", file.name, err);
+            return Err(OrganizerError::io(&path, err));
         }
 
-        self.source.remove_file(file.id);
+        self.source.remove_file(file.id)?;
 
-        self.source.load_from_store();
         self.update_ix_list();
 
         Ok(())
     }
 
-    pub fn get_label_filters(&self) -> Vec<LabelAutoFilter> {
+    pub fn get_label_filters(&self) -> Result<Vec<LabelAutoFilter>> {
         self.source.get_label_filters()
     }
 
-    pub fn add_update_label_filter(&mut self, filter: &LabelAutoFilter) {
-        self.source.add_update_label_filter(filter);
+    pub fn add_update_label_filter(&mut self, filter: &LabelAutoFilter) -> Result<()> {
+        self.source.add_update_label_filter(filter)
     }
 
-    pub fn delete_label_filter(&mut self, filter: &LabelAutoFilter) {
-        self.source.delete_label_filter(filter);
+    pub fn delete_label_filter(&mut self, filter: &LabelAutoFilter) -> Result<()> {
+        self.source.delete_label_filter(filter)
     }
 
     /// Return entry ids for all entries that match filter
@@ -567,7 +917,9 @@ impl Lens {
             .case_insensitive(true)
             .unicode(true)
             .build()
-            .context("Invalid regex string")?;
+            .map_err(|err| {
+                OrganizerError::InvalidInput(format!("Invalid regex string: {}", err))
+            })?;
 
         for entry in self.source.entriesCache.iter() {
             if re.is_match(&entry.name) {
@@ -578,3 +930,286 @@ impl Lens {
         Ok(id_list)
     }
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::dir_search::{get_all_data, ScanConfig, Scanner};
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn non_utf8_paths_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("loc");
+        let dir = root.join(OsStr::from_bytes(b"caf\xe9"));
+        let file = dir.join(OsStr::from_bytes(b"f\xf6o.txt"));
+        let single = root.join(OsStr::from_bytes(b"\xff.bin"));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&file, "abc").unwrap();
+        fs::write(&single, "x").unwrap();
+
+        let db = tmp.path().join("test.sqlite3");
+        let mut lens = Lens::new(db.to_str().unwrap()).unwrap();
+        lens.add_location("loc", root.to_str().unwrap()).unwrap();
+        let locations = lens.get_locations().unwrap();
+
+        let (mut data, report) = get_all_data(&locations);
+        assert!(report.iter().all(|r| r.is_empty()));
+        lens.update_data(&mut data, &report).unwrap();
+        assert_eq!(lens.get_dir_count(), 2);
+
+        let entry = lens
+            .source
+            .get_all_entries()
+            .iter()
+            .find(|e| e.fs_path() == dir)
+            .unwrap()
+            .clone();
+        assert_eq!(entry.name, "caf\u{FFFD}");
+        let files = lens.source.get_files(&entry).unwrap();
+        assert_eq!(files[0].fs_path(), file);
+
+        // Rescanning matches the stored paths instead of adding new entries
+        let ids: Vec<i32> = lens.source.get_all_entries().iter().map(|e| e.id).collect();
+        let (mut data, report) = get_all_data(&locations);
+        lens.update_data(&mut data, &report).unwrap();
+        let new_ids: Vec<i32> = lens.source.get_all_entries().iter().map(|e| e.id).collect();
+        assert_eq!(ids, new_ids);
+
+        lens.rename_entry(entry.clone(), "renamed").unwrap();
+        let renamed = lens.get_dir_entry_by_id(entry.id).unwrap().clone();
+        assert_eq!(renamed.fs_path(), root.join("renamed"));
+        let files = lens.source.get_files(&renamed).unwrap();
+        assert!(files[0].fs_path().exists());
+        assert_eq!(
+            files[0].fs_path(),
+            root.join("renamed").join(OsStr::from_bytes(b"f\xf6o.txt"))
+        );
+
+        let single_entry = lens
+            .source
+            .get_all_entries()
+            .iter()
+            .find(|e| e.fs_path() == single)
+            .unwrap()
+            .clone();
+        lens.remove_entry(&single_entry).unwrap();
+        assert!(!single.exists());
+        assert_eq!(lens.get_dir_count(), 1);
+    }
+
+    #[test]
+    fn files_are_searched() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("loc");
+        fs::create_dir_all(root.join("Show/Season 1")).unwrap();
+        fs::write(root.join("Show/Season 1/pilot.mkv"), "abc").unwrap();
+        fs::write(root.join("Show/notes.txt"), "abc").unwrap();
+        fs::write(root.join("pilot script.txt"), "x").unwrap();
+
+        let db = tmp.path().join("test.sqlite3");
+        let mut lens = Lens::new(db.to_str().unwrap()).unwrap();
+        lens.add_location("loc", root.to_str().unwrap()).unwrap();
+        let locations = lens.get_locations().unwrap();
+        let (mut data, report) = get_all_data(&locations);
+        lens.update_data(&mut data, &report).unwrap();
+
+        assert_eq!(lens.update_search_text("pilot").unwrap(), Some(1));
+        assert!(lens.get_matching_files(0).is_empty());
+
+        assert_eq!(lens.set_search_in_files(true), 2);
+        let show = (0..2)
+            .find(|ix| lens.get_dir_entry(*ix).unwrap().name == "Show")
+            .unwrap();
+        let matched = lens.get_matching_files(show);
+        assert_eq!(matched.len(), 1);
+        let file = lens.get_file_entry(show, matched[0]).unwrap();
+        assert_eq!(file.name, "pilot.mkv");
+        assert!(file.modified.is_some());
+        assert!(lens.get_dir_entry(show).unwrap().modified >= file.modified);
+
+        assert_eq!(lens.update_search_text("ext:mkv").unwrap(), Some(1));
+        assert!(lens.update_search_text("size:>lots").is_err());
+        assert_eq!(lens.get_dir_count(), 1);
+    }
+
+    #[test]
+    fn entries_are_filtered_by_file_type() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("loc");
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        for dir in ["Movie", "Album", "Docs"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        fs::write(
+            root.join("Movie/movie.MKV"),
+            b"\x1a\x45\xdf\xa3\x93\x42\x82\x88matroska",
+        )
+        .unwrap();
+        fs::write(root.join("Movie/cover.png"), png).unwrap();
+        fs::write(root.join("Album/a.png"), png).unwrap();
+        fs::write(root.join("Album/b.jpg"), b"\xff\xd8\xff\xe0 jfif").unwrap();
+        fs::write(root.join("Docs/readme.txt"), "text").unwrap();
+
+        let db = tmp.path().join("test.sqlite3");
+        let mut lens = Lens::new(db.to_str().unwrap()).unwrap();
+        lens.add_location("loc", root.to_str().unwrap()).unwrap();
+        let locations = lens.get_locations().unwrap();
+        let config = ScanConfig {
+            sniff_mime: true,
+            ..Default::default()
+        };
+        let (mut data, report) = Scanner::new().config(config).get_all_data(&locations);
+        lens.update_data(&mut data, &report).unwrap();
+
+        let names = |lens: &Lens| -> Vec<String> {
+            let mut names: Vec<String> = (0..lens.get_dir_count())
+                .map(|ix| lens.get_dir_entry(ix).unwrap().name.clone())
+                .collect();
+            names.sort();
+            names
+        };
+
+        lens.update_search_text("type:video").unwrap();
+        assert_eq!(names(&lens), ["Movie"]);
+        lens.update_search_text("only:image").unwrap();
+        assert_eq!(names(&lens), ["Album"]);
+        lens.update_search_text("type:image/png").unwrap();
+        assert_eq!(names(&lens), ["Album", "Movie"]);
+
+        let movie = lens.get_dir_entry(1).unwrap().clone();
+        let files = lens.source.get_files(&movie).unwrap();
+        let video = files.iter().find(|f| f.name == "movie.MKV").unwrap();
+        assert_eq!(video.extension.as_deref(), Some("mkv"));
+        assert_eq!(video.mime.as_deref(), Some("video/x-matroska"));
+        if cfg!(unix) {
+            assert!(video.mode.is_some_and(|mode| mode & 0o400 != 0));
+            assert!(video.owner.is_some());
+        }
+
+        // Scans without sniffing keep the types of unchanged files
+        fs::write(root.join("Album/b.jpg"), "no longer a jpeg").unwrap();
+        let (mut data, report) = get_all_data(&locations);
+        lens.update_data(&mut data, &report).unwrap();
+        lens.update_search_text("type:image").unwrap();
+        assert_eq!(names(&lens), ["Album", "Movie"]);
+        lens.update_search_text("only:image").unwrap();
+        assert!(names(&lens).is_empty());
+    }
+
+    #[test]
+    fn patched_caches_match_reload() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("loc");
+        fs::create_dir_all(root.join("dir/sub")).unwrap();
+        fs::write(root.join("dir/sub/a.txt"), "a").unwrap();
+        fs::write(root.join("dir/b.txt"), "bb").unwrap();
+        fs::write(root.join("single.txt"), "c").unwrap();
+        fs::write(root.join("gone.txt"), "d").unwrap();
+
+        let db = tmp.path().join("test.sqlite3");
+        let mut lens = Lens::new(db.to_str().unwrap()).unwrap();
+        lens.add_location("loc", root.to_str().unwrap()).unwrap();
+        lens.add_label("seen").unwrap();
+        let locations = lens.get_locations().unwrap();
+        let (mut data, report) = get_all_data(&locations);
+        lens.update_data(&mut data, &report).unwrap();
+
+        let by_name = |lens: &Lens, name: &str| {
+            lens.source
+                .get_all_entries()
+                .iter()
+                .find(|e| e.name == name)
+                .unwrap()
+                .clone()
+        };
+        let label_id = lens.get_labels()[0].id as u32;
+
+        let dir = by_name(&lens, "dir");
+        let single = by_name(&lens, "single.txt");
+        lens.add_entry_labels(vec![dir.id as u32, single.id as u32], vec![label_id])
+            .unwrap();
+        lens.remove_entry_labels(vec![single.id as u32], vec![label_id])
+            .unwrap();
+        lens.set_grade(dir.clone(), 4).unwrap();
+        lens.rename_entry(dir, "renamed").unwrap();
+        lens.move_file_entry_to_dir_entry(&single).unwrap();
+        let renamed = by_name(&lens, "renamed");
+        let file = lens.source.get_files(&renamed).unwrap()[0].clone();
+        lens.remove_file(&file).unwrap();
+        lens.remove_entry(&by_name(&lens, "gone.txt")).unwrap();
+
+        let reloaded = Lens::new(db.to_str().unwrap()).unwrap();
+        let entries = lens.source.get_all_entries();
+        assert_eq!(entries, reloaded.source.get_all_entries());
+        assert!(entries.windows(2).all(|w| w[0].id < w[1].id));
+        assert_eq!(by_name(&lens, "renamed").grade, Some(4));
+
+        for entry in entries {
+            assert_eq!(
+                format!("{:?}", lens.source.get_files(entry)),
+                format!("{:?}", reloaded.source.get_files(entry))
+            );
+            assert_eq!(
+                lens.source
+                    .entry_labels(entry.id)
+                    .cloned()
+                    .unwrap_or_default(),
+                reloaded
+                    .source
+                    .entry_labels(entry.id)
+                    .cloned()
+                    .unwrap_or_default()
+            );
+        }
+    }
+
+    #[test]
+    fn missing_entries_can_be_removed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("loc");
+        fs::create_dir_all(root.join("missing")).unwrap();
+        fs::write(root.join("missing/a.txt"), "a").unwrap();
+        fs::write(root.join("deleted.txt"), "b").unwrap();
+        fs::write(root.join("kept.txt"), "c").unwrap();
+
+        let db = tmp.path().join("test.sqlite3");
+        let mut lens = Lens::new(db.to_str().unwrap()).unwrap();
+        lens.add_location("loc", root.to_str().unwrap()).unwrap();
+        let locations = lens.get_locations().unwrap();
+        let (mut data, report) = get_all_data(&locations);
+        lens.update_data(&mut data, &report).unwrap();
+
+        // Marked missing by the next scan
+        fs::remove_dir_all(root.join("missing")).unwrap();
+        let (mut data, report) = get_all_data(&locations);
+        lens.update_data(&mut data, &report).unwrap();
+        assert_eq!(lens.set_missing_filter(MissingFilter::Only), 1);
+        let missing = lens.get_dir_entry(0).unwrap().clone();
+        assert!(missing.missing_since.is_some());
+        lens.remove_entry(&missing).unwrap();
+        assert_eq!(lens.get_dir_count(), 0);
+
+        // Deleted outside the app before a scan noticed
+        lens.set_missing_filter(MissingFilter::Show);
+        fs::remove_file(root.join("deleted.txt")).unwrap();
+        let deleted = lens
+            .source
+            .get_all_entries()
+            .iter()
+            .find(|e| e.name == "deleted.txt")
+            .unwrap()
+            .clone();
+        lens.remove_entry(&deleted).unwrap();
+
+        let names: Vec<&str> = lens
+            .source
+            .get_all_entries()
+            .iter()
+            .map(|e| e.name.as_str())
+            .collect();
+        assert_eq!(names, ["kept.txt"]);
+        assert_eq!(lens.get_dir_count(), 1);
+    }
+}
@@ -10,10 +10,17 @@ extern crate diesel;
 extern crate diesel_migrations;
 
 pub mod dir_search;
+pub mod error;
+pub mod hashing;
 pub mod lens;
 pub mod models;
+pub mod path_filter;
+pub mod query;
 pub mod schema;
+pub mod similar;
 pub mod store;
+#[cfg(all(feature = "watch", target_os = "linux"))]
+pub mod watcher;
 
 #[cfg(test)]
 mod tests {
@@ -2,29 +2,127 @@
 
 use crate::schema::*;
 
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Identifiable, Queryable, AsChangeset, Clone, Debug)]
 #[diesel(table_name = locations)]
 pub struct Location {
     pub id: i32,
     pub name: String,
     pub path: String,
+    /// Total size of all entries that are not missing, kept up to date by updates. Files hard
+    /// linked from several entries are counted once.
     pub size: i64,
+    /// False if the last scan could not read the location, its entries are kept as they were
+    pub online: bool,
+    /// Unix time in seconds of the last complete scan
+    pub last_scan: Option<i64>,
+    /// Duration of the last complete scan in milliseconds
+    pub scan_duration: Option<i64>,
+    /// Why the last scan could not read the location
+    pub last_error: Option<String>,
+    pub entry_count: i64,
+    pub file_count: i64,
+    /// How many levels below the location items become entries, 1 means the top level items.
+    /// Everything below an entry is flattened into its files.
+    pub entry_depth: i32,
+    /// Every file is its own entry at any depth, directories only group them
+    pub file_entries: bool,
+    /// Glob patterns, one per line, see `path_filter::PathFilter`
+    pub include_globs: String,
+    pub exclude_globs: String,
+    /// A `SymlinkPolicy`
+    pub symlinks: i32,
+}
+
+impl Location {
+    /// Unknown values are treated as the default, `SymlinkPolicy::Record`.
+    pub fn symlink_policy(&self) -> SymlinkPolicy {
+        SymlinkPolicy::from_i32(self.symlinks).unwrap_or(SymlinkPolicy::Record)
+    }
+
+    pub fn include_patterns(&self) -> impl Iterator<Item = &str> {
+        patterns(&self.include_globs)
+    }
+
+    /// Only the patterns of this location, `path_filter::DEFAULT_EXCLUDES` apply as well.
+    pub fn exclude_patterns(&self) -> impl Iterator<Item = &str> {
+        patterns(&self.exclude_globs)
+    }
+}
+
+/// How the scanner treats symbolic links.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[repr(i32)]
+pub enum SymlinkPolicy {
+    /// Links are left out
+    Ignore = 0,
+    /// Links are listed as empty files with their target, they are never followed
+    Record = 1,
+    /// Links are walked like the files and directories they point to, loops are reported as
+    /// skipped paths
+    Follow = 2,
+}
+
+impl SymlinkPolicy {
+    pub fn from_i32(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(SymlinkPolicy::Ignore),
+            1 => Some(SymlinkPolicy::Record),
+            2 => Some(SymlinkPolicy::Follow),
+            _ => None,
+        }
+    }
+}
+
+fn patterns(lines: &str) -> impl Iterator<Item = &str> {
+    lines.lines().map(str::trim).filter(|line| !line.is_empty())
 }
 
 #[derive(Clone, Debug)]
 pub struct DirEntry {
+    /// Display name, lossy if the file name is not valid UTF-8
     pub name: String,
     pub location_id: i32,
-    pub path: String,
+    pub path: PathBuf,
     pub files: Vec<FileEntry>,
     pub size: u64,
+    /// Modification time of every directory in the entry, the entry itself included.
+    /// A file entry records its own modification time.
+    pub dirs: Vec<(PathBuf, i64)>,
+    /// Unix time in seconds, `None` if the platform does not support it
+    pub created: Option<i64>,
+    /// Unix time in seconds of the newest modification of the entry or any file in it
+    pub modified: Option<i64>,
 }
 
 #[derive(Clone, Debug, Ord, PartialEq, Eq, PartialOrd)]
 pub struct FileEntry {
+    /// Display name, lossy if the file name is not valid UTF-8
     pub name: String,
-    pub path: String,
+    pub path: PathBuf,
     pub size: u64,
+    pub created: Option<i64>,
+    pub modified: Option<i64>,
+    /// Set if the file is a symbolic link that was recorded instead of followed
+    pub symlink_target: Option<PathBuf>,
+    /// Inode number, `None` on platforms without them
+    pub inode: Option<u64>,
+    /// Same file as one listed before it in the entry, through a hard link or a followed
+    /// symbolic link. Its size is not counted in the entry size again.
+    pub hard_link: bool,
+    /// Lower case, without the dot
+    pub extension: Option<String>,
+    /// Sniffed from the first bytes of the file if `ScanConfig::sniff_mime` is set, `None` if
+    /// it is not or the content is not recognized
+    pub mime: Option<String>,
+    /// Unix permission bits, `None` on other platforms
+    pub mode: Option<u32>,
+    /// User id of the owner, `None` on platforms without them
+    pub owner: Option<u32>,
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Identifiable, Queryable, AsChangeset, Clone, Debug)]
@@ -36,6 +134,27 @@ pub struct Entry {
     pub path: String,
     pub size: i64,
     pub grade: Option<i32>,
+    pub path_raw: Option<Vec<u8>>,
+    /// Unix time in seconds
+    pub created: Option<i64>,
+    /// Unix time in seconds
+    pub modified: Option<i64>,
+    /// Unix time in seconds of the first scan that did not find the entry, `None` if it exists
+    pub missing_since: Option<i64>,
+    /// Last streamed update that saw the entry, see `Store::update_streaming`
+    pub scan_generation: i32,
+}
+
+impl Entry {
+    /// The real path on disk, `path` is only for display if it is not valid UTF-8.
+    pub fn fs_path(&self) -> PathBuf {
+        from_stored_path(&self.path, self.path_raw.as_deref())
+    }
+
+    pub(crate) fn set_fs_path(&mut self, path: &Path) {
+        self.path = path.to_string_lossy().into_owned();
+        self.path_raw = raw_path(path);
+    }
 }
 
 #[derive(Identifiable, Queryable, AsChangeset, Clone, Debug)]
@@ -46,6 +165,367 @@ pub struct File {
     pub name: String,
     pub path: String,
     pub size: i64,
+    pub path_raw: Option<Vec<u8>>,
+    /// Content hash, only set for files that had the same size as another file when hashed
+    pub hash: Option<String>,
+    /// Unix time in seconds
+    pub created: Option<i64>,
+    /// Unix time in seconds
+    pub modified: Option<i64>,
+    /// Target of a recorded symbolic link, see `FileEntry::symlink_target`
+    pub symlink_target: Option<String>,
+    pub inode: Option<i64>,
+    /// See `FileEntry::hard_link`
+    pub hard_link: bool,
+    pub extension: Option<String>,
+    /// See `FileEntry::mime`, kept from an earlier scan while the file is not rewritten
+    pub mime: Option<String>,
+    pub mode: Option<i32>,
+    pub owner: Option<i64>,
+}
+
+impl File {
+    /// The real path on disk, `path` is only for display if it is not valid UTF-8.
+    pub fn fs_path(&self) -> PathBuf {
+        from_stored_path(&self.path, self.path_raw.as_deref())
+    }
+
+    pub(crate) fn set_fs_path(&mut self, path: &Path) {
+        self.path = path.to_string_lossy().into_owned();
+        self.path_raw = raw_path(path);
+    }
+
+    /// True if the MIME type is `mime`, or `mime` is its top level type like `video`.
+    pub fn has_type(&self, mime: &str) -> bool {
+        self.mime.as_deref().is_some_and(|own| {
+            own.eq_ignore_ascii_case(mime)
+                || own
+                    .split_once('/')
+                    .is_some_and(|(top, _)| top.eq_ignore_ascii_case(mime))
+        })
+    }
+}
+
+#[derive(Identifiable, Queryable, Clone, Debug)]
+#[diesel(table_name = dir_mtimes)]
+pub struct DirMtime {
+    pub id: i32,
+    pub entry_id: i32,
+    pub path: String,
+    pub mtime: i64,
+    pub path_raw: Option<Vec<u8>>,
+}
+
+impl DirMtime {
+    pub fn fs_path(&self) -> PathBuf {
+        from_stored_path(&self.path, self.path_raw.as_deref())
+    }
+}
+
+/// Directory modification times from the last scan, keyed by entry path.
+pub type KnownDirs = HashMap<PathBuf, Vec<(PathBuf, i64)>>;
+
+/// Result of an incremental scan, only holds entries that changed since the last scan.
+#[derive(Clone, Debug, Default)]
+pub struct ScanDelta {
+    pub changed: Vec<(i32, DirEntry)>,
+    /// Paths of entries that no longer exist on disk
+    pub removed: Vec<PathBuf>,
+    /// Paths of entries whose directories were unchanged and therefore not walked
+    pub skipped: Vec<PathBuf>,
+    pub reports: Vec<ScanReport>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    PermissionDenied,
+    BrokenLink,
+    /// Path was removed while the scan was running
+    Vanished,
+    /// Followed symbolic link pointing back to a directory above it
+    LinkLoop,
+    Other(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct SkippedPath {
+    pub path: PathBuf,
+    pub reason: SkipReason,
+}
+
+/// Paths of a location that could not be indexed during a scan.
+#[derive(Clone, Debug, Default)]
+pub struct ScanReport {
+    pub location_id: i32,
+    pub skipped: Vec<SkippedPath>,
+    /// The location itself could not be read, for example an unmounted drive. Its entries were
+    /// not scanned and must not be marked missing.
+    pub offline: bool,
+    /// Why the location is offline
+    pub error: Option<String>,
+    /// Time spent walking the whole location, `None` if only some entries were rescanned
+    pub duration: Option<Duration>,
+    /// The scan was stopped before all entries were walked, entries that were not reached
+    /// must not be marked missing.
+    pub cancelled: bool,
+}
+
+impl ScanReport {
+    pub fn new(location_id: i32) -> Self {
+        ScanReport {
+            location_id,
+            skipped: Vec::new(),
+            offline: false,
+            error: None,
+            duration: None,
+            cancelled: false,
+        }
+    }
+
+    /// Report for a location that could not be scanned at all.
+    pub fn offline(location_id: i32, error: impl Into<String>) -> Self {
+        ScanReport {
+            offline: true,
+            error: Some(error.into()),
+            ..ScanReport::new(location_id)
+        }
+    }
+
+    pub fn skip(&mut self, path: impl Into<PathBuf>, reason: SkipReason) {
+        self.skipped.push(SkippedPath {
+            path: path.into(),
+            reason,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.skipped.is_empty() && !self.offline && !self.cancelled
+    }
+
+    /// False if some entries of the location were not scanned.
+    pub fn is_complete(&self) -> bool {
+        !self.offline && !self.cancelled
+    }
+}
+
+/// Counts of a running scan of one location.
+#[derive(Clone, Debug, Default)]
+pub struct ScanProgress {
+    pub location_id: i32,
+    pub dirs: u64,
+    pub files: u64,
+    pub bytes: u64,
+    /// Entry or directory being walked
+    pub current: PathBuf,
+    /// Last update for the location, sent when it is done or was cancelled
+    pub done: bool,
+}
+
+/// Sent by `Scanner::send_all_data` for every scanned entry, each location ends with its report.
+#[derive(Clone, Debug)]
+pub enum ScanEvent {
+    Entry(i32, DirEntry),
+    Finished(ScanReport),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Resized {
+        old_size: u64,
+    },
+    /// Removed from `from` and added again with the same name and size
+    Moved {
+        from: PathBuf,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Changes made by an update to one location. `files` only lists changes inside entries that
+/// existed before and after the update, added and removed entries are only listed in `entries`.
+#[derive(Clone, Debug, Default)]
+pub struct LocationUpdate {
+    pub location_id: i32,
+    pub entries: Vec<Change>,
+    pub files: Vec<Change>,
+}
+
+impl LocationUpdate {
+    pub fn new(location_id: i32) -> Self {
+        LocationUpdate {
+            location_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.files.is_empty()
+    }
+
+    /// Turns each removed item that has an added item with the same name and size into a move.
+    pub(crate) fn detect_moves(&mut self) {
+        detect_moves(&mut self.entries);
+        detect_moves(&mut self.files);
+    }
+}
+
+fn detect_moves(changes: &mut Vec<Change>) {
+    let key = |change: &Change| {
+        (
+            change.path.file_name().map(OsStr::to_os_string),
+            change.size,
+        )
+    };
+
+    let (removed, mut rest): (Vec<Change>, Vec<Change>) = changes
+        .drain(..)
+        .partition(|c| c.kind == ChangeKind::Removed);
+
+    let mut removed_by_key: HashMap<_, Vec<usize>> = HashMap::new();
+    for (ix, change) in removed.iter().enumerate().rev() {
+        removed_by_key.entry(key(change)).or_default().push(ix);
+    }
+
+    let mut moved = vec![false; removed.len()];
+    for change in rest.iter_mut().filter(|c| c.kind == ChangeKind::Added) {
+        if let Some(ix) = removed_by_key.get_mut(&key(change)).and_then(|r| r.pop()) {
+            moved[ix] = true;
+            change.kind = ChangeKind::Moved {
+                from: removed[ix].path.clone(),
+            };
+        }
+    }
+
+    *changes = rest;
+    changes.extend(
+        removed
+            .into_iter()
+            .zip(moved)
+            .filter(|(_, moved)| !moved)
+            .map(|(change, _)| change),
+    );
+}
+
+/// What an update changed, per location ordered by location id.
+#[derive(Clone, Debug, Default)]
+pub struct UpdateReport {
+    pub locations: Vec<LocationUpdate>,
+}
+
+impl UpdateReport {
+    pub fn location(&self, location_id: i32) -> Option<&LocationUpdate> {
+        self.locations.iter().find(|l| l.location_id == location_id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locations.iter().all(|l| l.is_empty())
+    }
+}
+
+/// Totals over a set of entries, missing entries are not counted. Files hard linked from
+/// several entries of one location add to the size once.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Totals {
+    pub entries: usize,
+    pub files: usize,
+    pub size: u64,
+    /// Number of entries with a grade
+    pub graded: usize,
+    grade_sum: i64,
+    /// Location and inode of every counted file
+    inodes: HashSet<(i32, i64)>,
+}
+
+impl Totals {
+    pub(crate) fn add(&mut self, entry: &Entry, files: &[File]) {
+        self.entries += 1;
+        self.files += files.len();
+        let linked = linked_size(&mut self.inodes, entry.location_id, files);
+        self.size += (entry.size - linked).max(0) as u64;
+
+        if let Some(grade) = entry.grade {
+            self.graded += 1;
+            self.grade_sum += grade as i64;
+        }
+    }
+
+    /// Average over the graded entries, `None` if none are graded
+    pub fn average_grade(&self) -> Option<f64> {
+        if self.graded == 0 {
+            return None;
+        }
+
+        Some(self.grade_sum as f64 / self.graded as f64)
+    }
+}
+
+/// Size of the `files` whose inode is already in `counted`, which holds the location and inode
+/// of every file counted so far. Links inside one entry are already left out of its size.
+pub(crate) fn linked_size<'a>(
+    counted: &mut HashSet<(i32, i64)>,
+    location_id: i32,
+    files: impl IntoIterator<Item = &'a File>,
+) -> i64 {
+    files
+        .into_iter()
+        .filter(|file| !file.hard_link && file.symlink_target.is_none())
+        .filter_map(|file| Some((file.inode?, file.size)))
+        .filter(|(inode, _)| !counted.insert((location_id, *inode)))
+        .map(|(_, size)| size)
+        .sum()
+}
+
+#[derive(Clone, Debug)]
+pub struct LocationStats {
+    pub location: Location,
+    pub totals: Totals,
+}
+
+#[derive(Clone, Debug)]
+pub struct LabelStats {
+    pub label: Label,
+    pub totals: Totals,
+}
+
+/// Per location and per label totals, ordered like `get_locations` and `get_all_labels`.
+#[derive(Clone, Debug, Default)]
+pub struct Statistics {
+    pub locations: Vec<LocationStats>,
+    pub labels: Vec<LabelStats>,
+    pub total: Totals,
+}
+
+/// Files with identical content, the first file is considered the original.
+#[derive(Clone, Debug)]
+pub struct DuplicateGroup {
+    pub hash: String,
+    pub size: u64,
+    pub files: Vec<File>,
+}
+
+impl DuplicateGroup {
+    /// Bytes freed by removing all copies but one
+    pub fn reclaimable(&self) -> u64 {
+        self.size * (self.files.len() as u64 - 1)
+    }
+}
+
+/// Entries that are probably the same thing under slightly different names.
+#[derive(Clone, Debug)]
+pub struct SimilarGroup {
+    /// The normalized name shared by all entries
+    pub name: String,
+    /// Largest entry first
+    pub entries: Vec<Entry>,
+    /// Between 0 and 1, 1 means all entries have the same size
+    pub score: f64,
 }
 
 #[derive(Identifiable, Queryable, AsChangeset, Clone, Debug)]
@@ -87,3 +567,52 @@ impl LabelAutoFilterInsert {
         }
     }
 }
+
+/// Raw bytes of `path` if it is not valid UTF-8 and can not be stored as text, None otherwise.
+pub(crate) fn raw_path(path: &Path) -> Option<Vec<u8>> {
+    if path.to_str().is_some() {
+        return None;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Some(path.as_os_str().as_bytes().to_vec())
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        Some(
+            path.as_os_str()
+                .encode_wide()
+                .flat_map(|c| c.to_le_bytes())
+                .collect(),
+        )
+    }
+}
+
+fn from_stored_path(path: &str, raw: Option<&[u8]>) -> PathBuf {
+    let raw = match raw {
+        Some(raw) => raw,
+        None => return PathBuf::from(path),
+    };
+
+    #[cfg(unix)]
+    {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        PathBuf::from(OsStr::from_bytes(raw))
+    }
+
+    #[cfg(windows)]
+    {
+        use std::ffi::OsString;
+        use std::os::windows::ffi::OsStringExt;
+        let wide: Vec<u16> = raw
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        PathBuf::from(OsString::from_wide(&wide))
+    }
+}
@@ -0,0 +1,150 @@
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use log::warn;
+
+use crate::error::{OrganizerError, Result};
+use crate::models::Location;
+
+/// Excluded in every location: OS metadata, version control, unfinished downloads and samples.
+pub const DEFAULT_EXCLUDES: &[&str] = &[
+    ".DS_Store",
+    "Thumbs.db",
+    "desktop.ini",
+    ".git",
+    "*.part",
+    "*.crdownload",
+    "*.!qB",
+    "sample",
+    "*-sample.*",
+    "*.sample.*",
+];
+
+/// Decides which paths of a location are indexed. Patterns without a `/` match the name of an
+/// item at any depth, other patterns match the path relative to the location. Matching ignores
+/// case. An excluded directory is not walked at all. If there are include patterns only files
+/// matching one of them are indexed, directories are only subject to the excludes.
+#[derive(Debug, Clone)]
+pub struct PathFilter {
+    root: PathBuf,
+    excludes: GlobSet,
+    includes: Option<GlobSet>,
+}
+
+impl PathFilter {
+    /// Patterns that do not compile are logged and left out, `Store::set_location_globs` only
+    /// stores valid ones.
+    pub fn new(location: &Location) -> Self {
+        let excludes = build_set(
+            DEFAULT_EXCLUDES
+                .iter()
+                .copied()
+                .chain(location.exclude_patterns()),
+        );
+
+        let mut includes = location.include_patterns().peekable();
+        let includes = includes.peek().is_some().then(|| build_set(includes));
+
+        PathFilter {
+            root: PathBuf::from(&location.path),
+            excludes,
+            includes,
+        }
+    }
+
+    /// True if `path` and everything below it must not be indexed.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.matches(&self.excludes, path)
+    }
+
+    /// True if the file at `path` is indexed.
+    pub fn includes_file(&self, path: &Path) -> bool {
+        if self.is_excluded(path) {
+            return false;
+        }
+
+        match &self.includes {
+            Some(includes) => self.matches(includes, path),
+            None => true,
+        }
+    }
+
+    fn matches(&self, set: &GlobSet, path: &Path) -> bool {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+
+        set.is_match(relative)
+            || path
+                .file_name()
+                .is_some_and(|name| set.is_match(Path::new(name)))
+    }
+}
+
+/// Fails with the first pattern that is not a valid glob.
+pub fn validate_patterns(patterns: &[&str]) -> Result<()> {
+    for pattern in patterns {
+        glob(pattern).map_err(|err| {
+            OrganizerError::InvalidInput(format!("Bad glob pattern '{}': {}", pattern, err))
+        })?;
+    }
+
+    Ok(())
+}
+
+fn glob(pattern: &str) -> std::result::Result<Glob, globset::Error> {
+    GlobBuilder::new(pattern)
+        .case_insensitive(true)
+        .literal_separator(true)
+        .build()
+}
+
+fn build_set<'a>(patterns: impl Iterator<Item = &'a str>) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        match glob(pattern) {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(err) => warn!("Ignoring glob pattern {:?}: {}", pattern, err),
+        }
+    }
+
+    builder.build().unwrap_or_else(|err| {
+        warn!("Failed to build glob set: {}", err);
+        GlobSet::empty()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &str, exclude: &str) -> PathFilter {
+        PathFilter {
+            root: PathBuf::from("/loc"),
+            excludes: build_set(DEFAULT_EXCLUDES.iter().copied().chain(exclude.lines())),
+            includes: (!include.is_empty()).then(|| build_set(include.lines())),
+        }
+    }
+
+    #[test]
+    fn patterns_match_names_and_paths() {
+        let filter = filter("*.mkv\n*.srt", "extras\nmovies/old/*.mkv");
+        let excluded = |path: &str| filter.is_excluded(Path::new(path));
+        let included = |path: &str| filter.includes_file(Path::new(path));
+
+        assert!(excluded("/loc/a/b/Thumbs.db"));
+        assert!(excluded("/loc/a/thumbs.db"));
+        assert!(excluded("/loc/a/movie.mkv.part"));
+        assert!(excluded("/loc/movie/Sample"));
+        assert!(excluded("/loc/a/extras"));
+        assert!(excluded("/loc/movies/old/x.mkv"));
+        assert!(!excluded("/loc/movies/old/sub/x.mkv"));
+        assert!(!excluded("/loc/a/extras.mkv"));
+
+        assert!(included("/loc/a/movie.MKV"));
+        assert!(included("/loc/a/b/movie.srt"));
+        assert!(!included("/loc/a/movie.nfo"));
+        assert!(!included("/loc/a/movie-sample.mkv"));
+    }
+}
@@ -0,0 +1,620 @@
+//! Search query language used by `Lens::update_search_text`.
+//!
+//! Words are matched case insensitive against the entry name and all words must match.
+//! `"quoted phrases"` match as a whole, `-word` negates, `OR` between terms matches either side
+//! and binds weaker than the implicit AND. A `-` on its own is a word, as in `Artist - Album`.
+//! Qualifiers restrict a term to a field: `path:`, `label:`, `location:`, `ext:`, `type:`,
+//! `only:`, `grade:`, `size:`, `modified:` and `created:`, the last four take an optional
+//! comparison like `grade:>=4` or `size:>1.5GB`.
+//! `type:video` matches entries containing a file of that MIME type, `only:image` entries whose
+//! files all have it. Both take a top level type like `video` or a full one like `video/mp4`.
+//! Ungraded entries never match `grade:`, so `grade:<3` only lists entries graded 1 or 2.
+//!
+//! Dates are `YYYY`, `YYYY-MM` or `YYYY-MM-DD` in UTC and cover the whole period, so
+//! `modified:2023` matches all of 2023 and `modified:>2023-06` anything from July 2023 on.
+
+use thiserror::Error;
+use time::{Date, Month};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    fn compare<T: PartialOrd>(self, value: T, wanted: T) -> bool {
+        match self {
+            Comparison::Eq => value == wanted,
+            Comparison::Lt => value < wanted,
+            Comparison::Le => value <= wanted,
+            Comparison::Gt => value > wanted,
+            Comparison::Ge => value >= wanted,
+        }
+    }
+}
+
+/// A single condition, strings are stored lower case.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    Name(String),
+    Path(String),
+    Label(String),
+    Location(String),
+    Ext(String),
+    /// Contains a file with the MIME type
+    Type(String),
+    /// All files have the MIME type
+    OnlyType(String),
+    Grade(Comparison, i32),
+    Size(Comparison, u64),
+    /// Unix time range `[start, end)` of the given period
+    Created(Comparison, i64, i64),
+    Modified(Comparison, i64, i64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    All,
+    Term(Term),
+    Not(Box<Query>),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryErrorKind {
+    UnterminatedQuote,
+    EmptyTerm,
+    /// `OR` without a term on both sides
+    DanglingOr,
+    MissingValue(String),
+    InvalidGrade(String),
+    InvalidSize(String),
+    InvalidDate(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{kind:?} at position {position}")]
+pub struct QueryError {
+    /// Char offset into the query string
+    pub position: usize,
+    pub kind: QueryErrorKind,
+}
+
+/// What a query is evaluated against, implemented by the lens for its entries.
+pub trait QueryTarget {
+    fn name(&self) -> &str;
+    fn path(&self) -> &str;
+    fn size(&self) -> u64;
+    fn grade(&self) -> Option<i32>;
+    /// Unix time in seconds
+    fn created(&self) -> Option<i64>;
+    /// Unix time in seconds
+    fn modified(&self) -> Option<i64>;
+    /// `label` is lower case
+    fn has_label(&self, label: &str) -> bool;
+    /// `location` is lower case, matches if it is part of the location name
+    fn in_location(&self, location: &str) -> bool;
+    /// `ext` is lower case without dot
+    fn has_extension(&self, ext: &str) -> bool;
+    /// `mime` is lower case, a full MIME type or a top level type like `video`
+    fn has_type(&self, mime: &str) -> bool;
+    /// Like `has_type` but all files must have the type, false if there are no files
+    fn has_only_type(&self, mime: &str) -> bool;
+}
+
+impl Query {
+    pub fn matches(&self, target: &impl QueryTarget) -> bool {
+        match self {
+            Query::All => true,
+            Query::Term(term) => term.matches(target),
+            Query::Not(query) => !query.matches(target),
+            Query::And(queries) => queries.iter().all(|q| q.matches(target)),
+            Query::Or(queries) => queries.iter().any(|q| q.matches(target)),
+        }
+    }
+}
+
+impl Term {
+    fn matches(&self, target: &impl QueryTarget) -> bool {
+        match self {
+            Term::Name(text) => contains_lowercase(target.name(), text),
+            Term::Path(text) => contains_lowercase(target.path(), text),
+            Term::Label(label) => target.has_label(label),
+            Term::Location(location) => target.in_location(location),
+            Term::Ext(ext) => target.has_extension(ext),
+            Term::Type(mime) => target.has_type(mime),
+            Term::OnlyType(mime) => target.has_only_type(mime),
+            Term::Grade(cmp, grade) => target.grade().is_some_and(|g| cmp.compare(g, *grade)),
+            Term::Size(cmp, size) => cmp.compare(target.size(), *size),
+            Term::Created(cmp, start, end) => in_period(target.created(), *cmp, *start, *end),
+            Term::Modified(cmp, start, end) => in_period(target.modified(), *cmp, *start, *end),
+        }
+    }
+}
+
+/// Entries without a timestamp never match a date.
+fn in_period(time: Option<i64>, cmp: Comparison, start: i64, end: i64) -> bool {
+    let time = match time {
+        Some(time) => time,
+        None => return false,
+    };
+
+    match cmp {
+        Comparison::Eq => start <= time && time < end,
+        Comparison::Lt => time < start,
+        Comparison::Le => time < end,
+        Comparison::Gt => time >= end,
+        Comparison::Ge => time >= start,
+    }
+}
+
+fn contains_lowercase(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(needle)
+}
+
+#[derive(Debug)]
+enum Token {
+    Or(usize),
+    Word {
+        text: String,
+        negated: bool,
+        field: Option<String>,
+        position: usize,
+    },
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, QueryError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        if chars[i].is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let position = i;
+        // A lone dash separates parts of a name like "Artist - Album"
+        let negated = chars[i] == '-' && chars.get(i + 1).is_some_and(|c| !c.is_whitespace());
+        if negated {
+            i += 1;
+        }
+
+        let mut text = String::new();
+        let mut field = None;
+        let mut quoted = false;
+
+        while i < chars.len() && !chars[i].is_whitespace() {
+            match chars[i] {
+                '"' => {
+                    let start = i;
+                    i += 1;
+                    while i < chars.len() && chars[i] != '"' {
+                        text.push(chars[i]);
+                        i += 1;
+                    }
+                    if i == chars.len() {
+                        return Err(QueryError {
+                            position: start,
+                            kind: QueryErrorKind::UnterminatedQuote,
+                        });
+                    }
+                    quoted = true;
+                }
+                ':' if field.is_none() && !quoted && is_field(&text) => {
+                    field = Some(text.to_lowercase());
+                    text.clear();
+                }
+                c => text.push(c),
+            }
+            i += 1;
+        }
+
+        if !negated && !quoted && field.is_none() && text == "OR" {
+            tokens.push(Token::Or(position));
+            continue;
+        }
+
+        if text.is_empty() {
+            let kind = match field {
+                Some(field) => QueryErrorKind::MissingValue(field),
+                None => QueryErrorKind::EmptyTerm,
+            };
+            return Err(QueryError { position, kind });
+        }
+
+        tokens.push(Token::Word {
+            text,
+            negated,
+            field,
+            position,
+        });
+    }
+
+    Ok(tokens)
+}
+
+fn is_field(text: &str) -> bool {
+    matches!(
+        text.to_lowercase().as_str(),
+        "name"
+            | "path"
+            | "label"
+            | "location"
+            | "ext"
+            | "type"
+            | "only"
+            | "grade"
+            | "size"
+            | "created"
+            | "modified"
+    )
+}
+
+pub fn parse(input: &str) -> Result<Query, QueryError> {
+    let tokens = tokenize(input)?;
+
+    let mut groups: Vec<Query> = Vec::new();
+    let mut current: Vec<Query> = Vec::new();
+    let mut last_or = None;
+
+    for token in tokens {
+        match token {
+            Token::Or(position) => {
+                if current.is_empty() {
+                    return Err(dangling_or(position));
+                }
+                groups.push(and(std::mem::take(&mut current)));
+                last_or = Some(position);
+            }
+            Token::Word {
+                text,
+                negated,
+                field,
+                position,
+            } => {
+                let term = parse_term(field.as_deref(), &text, position)?;
+                let query = Query::Term(term);
+                current.push(if negated {
+                    Query::Not(Box::new(query))
+                } else {
+                    query
+                });
+            }
+        }
+    }
+
+    if current.is_empty() {
+        return match last_or {
+            Some(position) => Err(dangling_or(position)),
+            None => Ok(Query::All),
+        };
+    }
+    groups.push(and(current));
+
+    if groups.len() == 1 {
+        Ok(groups.pop().unwrap())
+    } else {
+        Ok(Query::Or(groups))
+    }
+}
+
+fn and(mut queries: Vec<Query>) -> Query {
+    if queries.len() == 1 {
+        queries.pop().unwrap()
+    } else {
+        Query::And(queries)
+    }
+}
+
+fn dangling_or(position: usize) -> QueryError {
+    QueryError {
+        position,
+        kind: QueryErrorKind::DanglingOr,
+    }
+}
+
+fn parse_term(field: Option<&str>, text: &str, position: usize) -> Result<Term, QueryError> {
+    let lower = text.to_lowercase();
+
+    let term = match field {
+        None | Some("name") => Term::Name(lower),
+        Some("path") => Term::Path(lower),
+        Some("label") => Term::Label(lower),
+        Some("location") => Term::Location(lower),
+        Some("ext") => Term::Ext(lower.trim_start_matches('.').to_string()),
+        Some("type") => Term::Type(lower),
+        Some("only") => Term::OnlyType(lower),
+        Some("grade") => {
+            let (cmp, value) = split_comparison(text);
+            let grade = value.parse().map_err(|_| QueryError {
+                position,
+                kind: QueryErrorKind::InvalidGrade(text.to_string()),
+            })?;
+            Term::Grade(cmp, grade)
+        }
+        Some("size") => {
+            let (cmp, value) = split_comparison(text);
+            let size = parse_size(value).ok_or_else(|| QueryError {
+                position,
+                kind: QueryErrorKind::InvalidSize(text.to_string()),
+            })?;
+            Term::Size(cmp, size)
+        }
+        Some(field @ ("created" | "modified")) => {
+            let (cmp, value) = split_comparison(text);
+            let (start, end) = parse_period(value).ok_or_else(|| QueryError {
+                position,
+                kind: QueryErrorKind::InvalidDate(text.to_string()),
+            })?;
+            if field == "created" {
+                Term::Created(cmp, start, end)
+            } else {
+                Term::Modified(cmp, start, end)
+            }
+        }
+        Some(_) => Term::Name(lower),
+    };
+
+    Ok(term)
+}
+
+fn split_comparison(text: &str) -> (Comparison, &str) {
+    for (prefix, cmp) in [
+        (">=", Comparison::Ge),
+        ("<=", Comparison::Le),
+        (">", Comparison::Gt),
+        ("<", Comparison::Lt),
+        ("=", Comparison::Eq),
+    ] {
+        if let Some(rest) = text.strip_prefix(prefix) {
+            return (cmp, rest);
+        }
+    }
+    (Comparison::Eq, text)
+}
+
+/// Parses sizes like `700`, `10KB`, `1.5GB` or `2t`, units are powers of 1000 like
+/// `lens::pretty_size`.
+fn parse_size(text: &str) -> Option<u64> {
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+
+    let number: f64 = number.parse().ok()?;
+    let multiplier: u64 = match unit.to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "m" | "mb" => 1000 * 1000,
+        "g" | "gb" => 1000 * 1000 * 1000,
+        "t" | "tb" => 1000 * 1000 * 1000 * 1000,
+        _ => return None,
+    };
+
+    Some((number * multiplier as f64) as u64)
+}
+
+/// Parses `YYYY`, `YYYY-MM` or `YYYY-MM-DD` into the unix time range the period covers.
+fn parse_period(text: &str) -> Option<(i64, i64)> {
+    let parts: Vec<&str> = text.split('-').collect();
+    // Month and day out of range fail to parse instead of wrapping around
+    let small = |ix: usize| -> Option<u8> { parts.get(ix)?.parse().ok() };
+    let month = |ix: usize| Month::try_from(small(ix)?).ok();
+
+    let year: i32 = parts[0].parse().ok()?;
+    let (start, end) = match parts.len() {
+        1 => (
+            Date::from_calendar_date(year, Month::January, 1).ok()?,
+            Date::from_calendar_date(year + 1, Month::January, 1).ok()?,
+        ),
+        2 => {
+            let start = Date::from_calendar_date(year, month(1)?, 1).ok()?;
+            let next = start.month().next();
+            let next_year = if next == Month::January {
+                year + 1
+            } else {
+                year
+            };
+            (start, Date::from_calendar_date(next_year, next, 1).ok()?)
+        }
+        3 => {
+            let start = Date::from_calendar_date(year, month(1)?, small(2)?).ok()?;
+            (start, start.next_day()?)
+        }
+        _ => return None,
+    };
+
+    let unix = |date: Date| date.midnight().assume_utc().unix_timestamp();
+    Some((unix(start), unix(end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Target {
+        name: &'static str,
+        size: u64,
+        grade: Option<i32>,
+        labels: &'static [&'static str],
+    }
+
+    impl QueryTarget for Target {
+        fn name(&self) -> &str {
+            self.name
+        }
+        fn path(&self) -> &str {
+            self.name
+        }
+        fn size(&self) -> u64 {
+            self.size
+        }
+        fn grade(&self) -> Option<i32> {
+            self.grade
+        }
+        fn created(&self) -> Option<i64> {
+            None
+        }
+        fn modified(&self) -> Option<i64> {
+            // 2023-06-15
+            Some(1_686_787_200)
+        }
+        fn has_label(&self, label: &str) -> bool {
+            self.labels.contains(&label)
+        }
+        fn in_location(&self, _location: &str) -> bool {
+            false
+        }
+        fn has_extension(&self, ext: &str) -> bool {
+            self.name.ends_with(ext)
+        }
+        fn has_type(&self, mime: &str) -> bool {
+            // A video with a cover image
+            let types: &[&str] = match self.name.rsplit_once('.') {
+                Some((_, "mkv")) => &["video", "image"],
+                _ => &["audio"],
+            };
+            types.contains(&mime)
+        }
+        fn has_only_type(&self, mime: &str) -> bool {
+            self.has_type(mime) && !self.name.ends_with(".mkv")
+        }
+    }
+
+    fn target(name: &'static str, size: u64, grade: Option<i32>) -> Target {
+        Target {
+            name,
+            size,
+            grade,
+            labels: &["seen"],
+        }
+    }
+
+    #[test]
+    fn queries_are_parsed() {
+        assert_eq!(parse("  ").unwrap(), Query::All);
+        assert_eq!(
+            parse(r#"a "b c" OR -label:Seen size:>1.5GB"#).unwrap(),
+            Query::Or(vec![
+                Query::And(vec![
+                    Query::Term(Term::Name("a".to_string())),
+                    Query::Term(Term::Name("b c".to_string())),
+                ]),
+                Query::And(vec![
+                    Query::Not(Box::new(Query::Term(Term::Label("seen".to_string())))),
+                    Query::Term(Term::Size(Comparison::Gt, 1_500_000_000)),
+                ]),
+            ])
+        );
+        assert_eq!(
+            parse("Re:Zero").unwrap(),
+            Query::Term(Term::Name("re:zero".to_string()))
+        );
+    }
+
+    #[test]
+    fn errors_have_positions() {
+        let err = |q| parse(q).unwrap_err();
+
+        assert_eq!(err(r#"a "b"#).kind, QueryErrorKind::UnterminatedQuote);
+        assert_eq!(err(r#"a "b"#).position, 2);
+        assert_eq!(err("a OR").kind, QueryErrorKind::DanglingOr);
+        assert_eq!(err("a OR").position, 2);
+        assert_eq!(err("OR a").kind, QueryErrorKind::DanglingOr);
+        assert_eq!(
+            err("a grade:x").kind,
+            QueryErrorKind::InvalidGrade("x".to_string())
+        );
+        assert_eq!(err("a grade:x").position, 2);
+        assert_eq!(
+            err("size:>lots").kind,
+            QueryErrorKind::InvalidSize(">lots".to_string())
+        );
+        assert_eq!(
+            err("path:").kind,
+            QueryErrorKind::MissingValue("path".to_string())
+        );
+        assert_eq!(err(r#"a -"""#).kind, QueryErrorKind::EmptyTerm);
+        assert_eq!(err(r#"a -"""#).position, 2);
+    }
+
+    #[test]
+    fn dates_cover_periods() {
+        assert_eq!(parse_period("1970"), Some((0, 365 * 86400)));
+        assert_eq!(parse_period("1970-02"), Some((31 * 86400, 59 * 86400)));
+        assert_eq!(parse_period("1970-12"), Some((334 * 86400, 365 * 86400)));
+        assert_eq!(parse_period("1970-01-02"), Some((86400, 2 * 86400)));
+        assert_eq!(parse_period("1970-13"), None);
+        assert_eq!(parse_period("1970-02-30"), None);
+        assert_eq!(parse_period("2023-257"), None);
+        assert_eq!(parse_period("2023-06-257"), None);
+
+        let movie = target("Some Movie.mkv", 1, None);
+        for (q, expected) in [
+            ("modified:2023", true),
+            ("modified:2023-06-15", true),
+            ("modified:2023-06-14", false),
+            ("modified:>2023-05 modified:<2023-07", true),
+            ("modified:>2023-06", false),
+            ("modified:<=2023-06", true),
+            ("created:<2030", false),
+        ] {
+            assert_eq!(parse(q).unwrap().matches(&movie), expected, "{}", q);
+        }
+
+        assert_eq!(
+            parse("modified:yesterday").unwrap_err().kind,
+            QueryErrorKind::InvalidDate("yesterday".to_string())
+        );
+        assert_eq!(
+            parse("modified:2023-257").unwrap_err().kind,
+            QueryErrorKind::InvalidDate("2023-257".to_string())
+        );
+    }
+
+    #[test]
+    fn queries_match_targets() {
+        let movie = target("Some Movie.mkv", 2_000_000_000, Some(4));
+        let song = target("Some Song.mp3", 5_000_000, None);
+
+        let q = parse("some ext:mkv grade:>=4").unwrap();
+        assert!(q.matches(&movie));
+        assert!(!q.matches(&song));
+
+        let q = parse("-movie size:<10MB OR grade:4").unwrap();
+        assert!(q.matches(&movie));
+        assert!(q.matches(&song));
+
+        // Ungraded entries never match a grade
+        assert!(!parse("grade:<3").unwrap().matches(&song));
+        assert!(parse("-grade:>=3").unwrap().matches(&song));
+
+        let q = parse("label:seen -\"some song\"").unwrap();
+        assert!(q.matches(&movie));
+        assert!(!q.matches(&song));
+
+        let q = parse("type:video OR only:audio").unwrap();
+        assert!(q.matches(&movie));
+        assert!(q.matches(&song));
+        assert_eq!(
+            parse("only:Image").unwrap(),
+            Query::Term(Term::OnlyType("image".to_string()))
+        );
+        assert!(!parse("only:image").unwrap().matches(&movie));
+
+        // Dashes between words are part of the name
+        let album = target("Artist - Album", 1, None);
+        for q in ["Artist - Album", "artist -", "- album"] {
+            assert!(parse(q).unwrap().matches(&album), "{}", q);
+        }
+        assert!(!parse("Artist - Single").unwrap().matches(&album));
+        assert!(!parse("Artist -album").unwrap().matches(&album));
+    }
+}
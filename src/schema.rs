@@ -1,5 +1,15 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    dir_mtimes (id) {
+        id -> Integer,
+        entry_id -> Integer,
+        path -> Text,
+        mtime -> BigInt,
+        path_raw -> Nullable<Binary>,
+    }
+}
+
 diesel::table! {
     entries (id) {
         id -> Integer,
@@ -8,6 +18,11 @@ diesel::table! {
         path -> Text,
         size -> BigInt,
         grade -> Nullable<Integer>,
+        path_raw -> Nullable<Binary>,
+        created -> Nullable<BigInt>,
+        modified -> Nullable<BigInt>,
+        missing_since -> Nullable<BigInt>,
+        scan_generation -> Integer,
     }
 }
 
@@ -25,6 +40,17 @@ diesel::table! {
         name -> Text,
         path -> Text,
         size -> BigInt,
+        path_raw -> Nullable<Binary>,
+        hash -> Nullable<Text>,
+        created -> Nullable<BigInt>,
+        modified -> Nullable<BigInt>,
+        symlink_target -> Nullable<Text>,
+        inode -> Nullable<BigInt>,
+        hard_link -> Bool,
+        extension -> Nullable<Text>,
+        mime -> Nullable<Text>,
+        mode -> Nullable<Integer>,
+        owner -> Nullable<BigInt>,
     }
 }
 
@@ -50,9 +76,21 @@ diesel::table! {
         name -> Text,
         path -> Text,
         size -> BigInt,
+        online -> Bool,
+        last_scan -> Nullable<BigInt>,
+        scan_duration -> Nullable<BigInt>,
+        last_error -> Nullable<Text>,
+        entry_count -> BigInt,
+        file_count -> BigInt,
+        entry_depth -> Integer,
+        file_entries -> Bool,
+        include_globs -> Text,
+        exclude_globs -> Text,
+        symlinks -> Integer,
     }
 }
 
+diesel::joinable!(dir_mtimes -> entries (entry_id));
 diesel::joinable!(entries -> locations (location_id));
 diesel::joinable!(entry2labels -> entries (entry_id));
 diesel::joinable!(entry2labels -> labels (label_id));
@@ -60,6 +98,7 @@ diesel::joinable!(files -> entries (entry_id));
 diesel::joinable!(label_auto_filters -> labels (label_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    dir_mtimes,
     entries,
     entry2labels,
     files,
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+
+use crate::models::{Entry, SimilarGroup};
+
+/// Tokens that mark the start of the release info in scene style names, everything from the
+/// first of them is dropped.
+const RELEASE_TAGS: &[&str] = &[
+    "480p", "576p", "720p", "1080p", "1080i", "2160p", "4k", "uhd", "hdr", "x264", "x265", "h264",
+    "h265", "hevc", "xvid", "divx", "bluray", "bdrip", "brrip", "webrip", "webdl", "hdtv",
+    "dvdrip", "remux", "repack", "aac", "ac3", "dts", "flac", "mp3", "10bit",
+];
+
+/// Release tags that are also ordinary words, like in "The Web". They only mark the release
+/// info after a year.
+const WORD_TAGS: &[&str] = &["web", "dvd", "proper", "extended", "unrated"];
+
+/// Normalizes an entry name for comparison: lower case, no bracketed parts except years, no
+/// punctuation, no file extension and nothing after the first release tag.
+pub fn normalize_name(name: &str) -> String {
+    let name = name.to_lowercase();
+    let name = strip_extension(&name);
+
+    let mut cleaned = String::with_capacity(name.len());
+    let mut bracket: Option<(char, String)> = None;
+
+    for c in name.chars() {
+        match (&mut bracket, c) {
+            (None, '(') => bracket = Some((')', String::new())),
+            (None, '[') => bracket = Some((']', String::new())),
+            (None, '{') => bracket = Some(('}', String::new())),
+            (Some((close, inner)), c) if c == *close => {
+                // Keep years, they tell remakes apart
+                if is_year(inner) {
+                    cleaned.push(' ');
+                    cleaned.push_str(inner);
+                }
+                cleaned.push(' ');
+                bracket = None;
+            }
+            (Some((_, inner)), c) => inner.push(c),
+            (None, c) if c.is_alphanumeric() => cleaned.push(c),
+            (None, '-') | (None, '_') | (None, '.') | (None, ' ') => cleaned.push(' '),
+            (None, _) => {}
+        }
+    }
+
+    let mut after_year = false;
+    cleaned
+        .split_whitespace()
+        .take_while(|token| {
+            let tag = RELEASE_TAGS.contains(token) || (after_year && WORD_TAGS.contains(token));
+            after_year |= is_year(token);
+            !tag
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn strip_extension(name: &str) -> &str {
+    if let Some((stem, ext)) = name.rsplit_once('.') {
+        if !stem.is_empty()
+            && (2..=4).contains(&ext.len())
+            && ext.chars().all(|c| c.is_ascii_alphanumeric())
+            && ext.chars().any(|c| c.is_ascii_alphabetic())
+        {
+            return stem;
+        }
+    }
+    name
+}
+
+fn is_year(s: &str) -> bool {
+    s.len() == 4
+        && s.chars().all(|c| c.is_ascii_digit())
+        && (s.starts_with("19") || s.starts_with("20"))
+}
+
+/// Clusters entries with the same normalized name whose sizes differ at most `size_tolerance`
+/// (0.1 = 10%) from the largest entry in the cluster. Groups with the highest score come first.
+pub fn find_similar_entries(entries: &[Entry], size_tolerance: f64) -> Vec<SimilarGroup> {
+    let mut by_name: HashMap<String, Vec<&Entry>> = HashMap::new();
+
+    for entry in entries.iter() {
+        let key = normalize_name(&entry.name);
+        if !key.is_empty() {
+            by_name.entry(key).or_default().push(entry);
+        }
+    }
+
+    let mut groups = Vec::new();
+
+    for (key, mut candidates) in by_name.into_iter().filter(|(_, c)| c.len() > 1) {
+        candidates.sort_by_key(|entry| std::cmp::Reverse(entry.size));
+
+        let mut cluster: Vec<&Entry> = Vec::new();
+        for entry in candidates {
+            if let Some(largest) = cluster.first() {
+                if !within_tolerance(largest.size, entry.size, size_tolerance) {
+                    push_group(&mut groups, &key, &cluster);
+                    cluster.clear();
+                }
+            }
+            cluster.push(entry);
+        }
+        push_group(&mut groups, &key, &cluster);
+    }
+
+    groups.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    groups
+}
+
+fn within_tolerance(largest: i64, size: i64, size_tolerance: f64) -> bool {
+    if largest <= 0 {
+        return size <= 0;
+    }
+    (largest - size) as f64 / largest as f64 <= size_tolerance
+}
+
+fn push_group(groups: &mut Vec<SimilarGroup>, key: &str, cluster: &[&Entry]) {
+    if cluster.len() < 2 {
+        return;
+    }
+
+    let largest = cluster[0].size.max(1) as f64;
+    let smallest = cluster[cluster.len() - 1].size.max(0) as f64;
+
+    groups.push(SimilarGroup {
+        name: key.to_string(),
+        entries: cluster.iter().map(|e| (*e).clone()).collect(),
+        // 1.0 when all entries have the same size
+        score: smallest / largest,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: i32, location_id: i32, name: &str, size: i64) -> Entry {
+        Entry {
+            id,
+            location_id,
+            name: name.to_string(),
+            path: format!("/loc{}/{}", location_id, name),
+            size,
+            grade: None,
+            path_raw: None,
+            created: None,
+            modified: None,
+            missing_since: None,
+            scan_generation: 0,
+        }
+    }
+
+    #[test]
+    fn names_are_normalized() {
+        assert_eq!(
+            normalize_name("Some.Movie.2010.1080p.BluRay.x264-GRP"),
+            "some movie 2010"
+        );
+        assert_eq!(
+            normalize_name("Some Movie (2010) [Extended]"),
+            "some movie 2010"
+        );
+        assert_eq!(normalize_name("some_movie_2010.mkv"), "some movie 2010");
+        assert_eq!(normalize_name("Album Vol.2"), "album vol 2");
+
+        // Tags that are words only count after a year
+        assert_eq!(normalize_name("The Web"), "the web");
+        assert_eq!(
+            normalize_name("Extended Family (2015)"),
+            "extended family 2015"
+        );
+        assert_eq!(
+            normalize_name("The.Web.2014.EXTENDED.WEB.x264"),
+            "the web 2014"
+        );
+        assert_ne!(normalize_name("The Web"), normalize_name("The Proper Way"));
+    }
+
+    #[test]
+    fn entries_are_clustered_by_name_and_size() {
+        let entries = vec![
+            entry(1, 1, "Some.Movie.2010.1080p.BluRay.x264-GRP", 1000),
+            entry(2, 2, "Some Movie (2010)", 990),
+            entry(3, 2, "Some Movie (2010) [Sample]", 10),
+            entry(4, 1, "Other Movie", 1000),
+        ];
+
+        let groups = find_similar_entries(&entries, 0.1);
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].name, "some movie 2010");
+        let ids: Vec<i32> = groups[0].entries.iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert!((groups[0].score - 0.99).abs() < 1e-9);
+    }
+}
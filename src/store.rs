@@ -10,8 +10,12 @@ use diesel::prelude::*;
 
 use diesel_migrations::{self, EmbeddedMigrations, MigrationHarness};
 
+use crate::error::{OrganizerError, Result};
+use crate::hashing::{content_hash, hash_candidates, HashCandidate};
 use crate::models::*;
+use crate::path_filter::validate_patterns;
 
+use crate::schema::dir_mtimes::dsl as dm;
 use crate::schema::entries::dsl as e;
 use crate::schema::entry2labels::dsl as e2l;
 use crate::schema::files::dsl as f;
@@ -19,87 +23,109 @@ use crate::schema::label_auto_filters::dsl as aut;
 use crate::schema::labels::dsl as l;
 use crate::schema::locations::dsl as loc;
 
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 
-use time::Instant;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 //use schema::*;
 
-// TODO: Enable migrations again
-// embed_migrations!();
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
+/// WAL lets readers continue while a scan writes, the busy timeout makes a second connection
+/// wait for the lock instead of failing right away.
+const CONNECTION_PRAGMAS: &str = "
+    PRAGMA foreign_keys = ON;
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    PRAGMA busy_timeout = 5000;
+    PRAGMA temp_store = MEMORY;
+    PRAGMA cache_size = -16000;
+";
+
+/// How long missing entries are kept before they are purged, 30 days
+const DEFAULT_MISSING_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+fn open_connection(db_url: &str) -> Result<SqliteConnection> {
+    let mut connection = SqliteConnection::establish(db_url)?;
+
+    connection.batch_execute(CONNECTION_PRAGMAS)?;
+
+    Ok(connection)
+}
+
 pub struct Store {
     db_url: String,
+    /// Kept open for the lifetime of the store, `&self` methods borrow it at runtime
+    connection: RefCell<SqliteConnection>,
     pub entriesCache: Vec<Entry>,
     filesCache: HashMap<i32, Vec<File>>,
     labelsCache: Vec<Label>,
     labelLookupCache: HashMap<i32, HashSet<i32>>,
     entryLabelLookup: HashMap<i32, HashSet<i32>>,
+    missing_retention: Duration,
 }
 
 impl Store {
-    pub fn init(db_url: &str) -> Store {
+    pub fn init(db_url: &str) -> Result<Store> {
         use std::fs::File;
         use std::path::Path;
 
         let db_path = Path::new(db_url);
         if !db_path.exists() {
-            File::create(db_path).expect(&format!("Failed to create db_file: {:?}", db_path));
+            File::create(db_path).map_err(|err| OrganizerError::io(db_path, err))?;
+        }
+
+        let mut connection = open_connection(db_url)?;
+
+        let migs = connection
+            .run_pending_migrations(MIGRATIONS)
+            .map_err(OrganizerError::Migration)?;
+
+        if !migs.is_empty() {
+            info!("Got migrations! {:?}", migs)
+        } else {
+            info!("No migrations.")
         }
 
         let store = Store {
             db_url: db_url.to_string(),
+            connection: RefCell::new(connection),
             entriesCache: Vec::new(),
             filesCache: HashMap::new(),
             labelsCache: Vec::new(),
             labelLookupCache: HashMap::new(),
             entryLabelLookup: HashMap::new(),
+            missing_retention: DEFAULT_MISSING_RETENTION,
         };
 
-        let mut connection = store.establish_connection();
-
-        match connection.run_pending_migrations(MIGRATIONS) {
-            Ok(migs) => {
-                if !migs.is_empty() {
-                    println!("Got migrations! {:?}", migs)
-                } else {
-                    println!("No migrations.")
-                }
-            }
-            Err(err) => eprintln!("Failed to run migrations: {}", err),
-        }
-
-        store
+        Ok(store)
     }
 
-    pub fn establish_connection(&self) -> SqliteConnection {
-        let mut connection = SqliteConnection::establish(&self.db_url)
-            .expect("Failed to establish connection to sqlite");
-
-        connection
-            .batch_execute("PRAGMA foreign_keys = ON")
-            .expect("Failed to set pragmas");
-
-        return connection;
+    /// Opens an extra connection to the store database with the same settings as the one the
+    /// store keeps, for use from other threads.
+    pub fn establish_connection(&self) -> Result<SqliteConnection> {
+        open_connection(&self.db_url)
     }
 
     /*** Load cache ***/
-    pub fn load_from_store(&mut self) {
-        let mut conn = self.establish_connection();
+    pub fn load_from_store(&mut self) -> Result<()> {
+        let conn = self.connection.get_mut();
         //        conn.execute("DELETE FROM entries").unwrap();
 
-        self.entriesCache = e::entries.load(&mut conn).expect("Failed to load entries");
-        self.load_files(&mut conn);
-        self.labelsCache = l::labels.load(&mut conn).expect("Failed to load labels");
-        self.load_labels(&mut conn);
+        self.entriesCache = e::entries.load(conn)?;
+        self.labelsCache = l::labels.load(conn)?;
+        self.load_files()?;
+        self.load_labels()?;
 
         // Sort entries
         self.entriesCache.sort_by_key(|e| e.id);
+
+        Ok(())
     }
 
-    fn load_files(&mut self, connection: &mut SqliteConnection) {
-        let files: Vec<File> = f::files.load(connection).expect("Failed to load files");
+    fn load_files(&mut self) -> Result<()> {
+        let files: Vec<File> = f::files.load(self.connection.get_mut())?;
 
         for entry in self.entriesCache.iter() {
             self.filesCache.insert(entry.id, Vec::new());
@@ -108,165 +134,716 @@ impl Store {
         debug!("Got {} files in filecache", self.filesCache.len());
 
         for file in files {
-            let files = self.filesCache.get_mut(&file.entry_id).expect(&format!(
-                "Did not find files that really should be there: {:?} path: {:?}",
-                file.entry_id, file.path
-            ));
+            let files = self.filesCache.get_mut(&file.entry_id).ok_or_else(|| {
+                OrganizerError::NotFound(format!(
+                    "Entry {} for file {:?}",
+                    file.entry_id, file.path
+                ))
+            })?;
             files.push(file);
         }
 
         debug!("Now got files for entries {}", self.filesCache.len());
+
+        Ok(())
     }
 
-    fn load_labels(&mut self, connection: &mut SqliteConnection) {
-        let entry2label: Vec<Entry2Label> = e2l::entry2labels
-            .load(connection)
-            .expect("Failed to load entry mapping");
+    fn load_labels(&mut self) -> Result<()> {
+        let entry2label: Vec<Entry2Label> = e2l::entry2labels.load(self.connection.get_mut())?;
 
         let mut lbl_map: HashMap<i32, HashSet<i32>> = HashMap::new();
 
         for e2l in entry2label.iter() {
-            let set = lbl_map.entry(e2l.label_id).or_insert(HashSet::new());
+            let set = lbl_map.entry(e2l.label_id).or_default();
             set.insert(e2l.entry_id);
         }
 
         let mut entry_map: HashMap<i32, HashSet<i32>> = HashMap::new();
 
         for e2l in entry2label.iter() {
-            let set = entry_map.entry(e2l.entry_id).or_insert(HashSet::new());
+            let set = entry_map.entry(e2l.entry_id).or_default();
             set.insert(e2l.label_id);
         }
 
         self.entryLabelLookup = entry_map;
         self.labelLookupCache = lbl_map;
-    }
 
-    pub fn update(&mut self, dir_entries: &Vec<(i32, DirEntry)>) {
-        use std::collections::HashMap;
-        use std::collections::HashSet;
+        Ok(())
+    }
 
+    /// Entries that vanished are kept and marked missing for `missing_retention`, in case
+    /// they show up again. Nothing is marked missing in locations the scan reported offline or
+    /// did not finish.
+    pub fn update(
+        &mut self,
+        dir_entries: &[(i32, DirEntry)],
+        reports: &[ScanReport],
+    ) -> Result<UpdateReport> {
         debug!("Starting update");
+
+        let paths: HashSet<&Path> = dir_entries
+            .iter()
+            .map(|(_, dir)| dir.path.as_path())
+            .collect();
+
+        let incomplete: HashSet<i32> = reports
+            .iter()
+            .filter(|r| !r.is_complete())
+            .map(|r| r.location_id)
+            .collect();
+
+        let removed = self
+            .entriesCache
+            .iter()
+            .filter(|entry| !incomplete.contains(&entry.location_id))
+            .filter(|entry| !paths.contains(entry.fs_path().as_path()))
+            .map(|entry| entry.id)
+            .collect();
+
+        self.apply_changes(dir_entries, removed, &paths, reports)
+    }
+
+    /// Applies a scan from `dir_search::Scanner::send_all_data` while it runs, usually read
+    /// from a bounded channel. Entries are written in transactions of at most `batch_size`
+    /// entries and only diffed against their own stored rows, so the scan never holds more than
+    /// a batch in memory. Every entry written is stamped with a new scan generation. Once the
+    /// `ScanEvent::Finished` report of a location says it was scanned completely, its entries
+    /// without that generation were not seen. Like with `update`, unseen entries replaced by a
+    /// new entry of the same location keep their labels and grade, the rest is marked missing.
+    /// Location status and totals are written when its report arrives. Unlike `update` a failed
+    /// write leaves the batches written before it in place.
+    pub fn update_streaming(
+        &mut self,
+        events: impl IntoIterator<Item = ScanEvent>,
+        batch_size: usize,
+    ) -> Result<UpdateReport> {
+        use diesel::dsl::max;
+
+        debug!("Starting streaming update");
+
         let start = Instant::now();
+        let batch_size = batch_size.max(1);
+
+        // Entries inserted by this update get ids above `last_id`
+        let connection = self.connection.get_mut();
+        let generation: Option<i32> = e::entries
+            .select(max(e::scan_generation))
+            .first(connection)?;
+        let last_id: Option<i32> = e::entries.select(max(e::id)).first(connection)?;
+        let generation = generation.unwrap_or(0) + 1;
+        let mut last_id = last_id.unwrap_or(0);
+
+        let mut batch = Vec::with_capacity(batch_size);
+        let mut unfinished = HashSet::new();
+        let mut count = 0;
+        let mut updates: HashMap<i32, LocationUpdate> = HashMap::new();
+
+        for event in events {
+            match event {
+                ScanEvent::Entry(location_id, dir) => {
+                    count += 1;
+                    unfinished.insert(location_id);
+                    batch.push((location_id, dir));
+                    if batch.len() >= batch_size {
+                        self.apply_batch(&batch, generation, &mut updates)?;
+                        batch.clear();
+                    }
+                }
+                ScanEvent::Finished(report) => {
+                    // The last entries of the location may still be waiting
+                    self.apply_batch(&batch, generation, &mut updates)?;
+                    batch.clear();
+
+                    unfinished.remove(&report.location_id);
+                    self.finish_location(&report, generation, last_id, &mut updates)?;
+
+                    // Ids are reused once the largest one is deleted
+                    let max_id: Option<i32> = e::entries
+                        .select(max(e::id))
+                        .first(self.connection.get_mut())?;
+                    last_id = last_id.min(max_id.unwrap_or(0));
+                }
+            }
+        }
+        self.apply_batch(&batch, generation, &mut updates)?;
 
-        let mut dir_hash = HashMap::with_capacity(dir_entries.len());
+        // Locations whose scan stopped without a report
+        let connection = self.connection.get_mut();
+        for location_id in unfinished {
+            write_location_total(connection, location_id)?;
+        }
 
-        for (location_id, dir) in dir_entries.iter() {
-            dir_hash.insert(&dir.path, dir);
+        info!(
+            "Streaming update of {} entries took: {:?} ms",
+            count,
+            start.elapsed().as_millis()
+        );
+
+        let mut locations: Vec<LocationUpdate> = updates.into_values().collect();
+        locations.sort_by_key(|l| l.location_id);
+        for location in locations.iter_mut() {
+            location.detect_moves();
         }
 
-        let mut connection = self.establish_connection();
+        Ok(UpdateReport { locations })
+    }
 
-        let mut collisions = HashSet::new();
-        for entry in self.entriesCache.iter() {
-            if let Some(dir_entry) = dir_hash.get(&entry.path) {
-                // Update existing entries
-                collisions.insert(entry.path.clone());
-                let new_size = dir_entry.size as i64;
-                if entry.size != new_size {
-                    // trace!("Update entry: {} {}", entry.path, entry.name);
-                    diesel::update(entry)
-                        .set(e::size.eq(new_size))
-                        .execute(&mut connection)
-                        .expect("Failed to update entry");
-                }
-            } else {
-                // Delete entries not in entries
-                //                trace!("Delete entry: {} {}", entry.path, entry.name);
-                diesel::delete(e::entries.filter(e::id.eq(entry.id)))
-                    .execute(&mut connection)
-                    .expect("Failed to delete entry");
+    /// Writes one batch of a streamed update in a transaction and marks its entries as seen by
+    /// `generation`. Only the stored rows and files of the batch are read and cached again.
+    fn apply_batch(
+        &mut self,
+        batch: &[(i32, DirEntry)],
+        generation: i32,
+        updates: &mut HashMap<i32, LocationUpdate>,
+    ) -> Result<()> {
+        use diesel::dsl::max;
+        use diesel::result::Error;
+
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let connection = self.connection.get_mut();
+        let paths: Vec<String> = batch
+            .iter()
+            .map(|(_, dir)| dir.path.to_string_lossy().into_owned())
+            .collect();
+        let mut stored: HashMap<PathBuf, Entry> = HashMap::with_capacity(batch.len());
+        for slice in paths.chunks(5000) {
+            for entry in e::entries
+                .filter(e::path.eq_any(slice))
+                .load::<Entry>(connection)?
+            {
+                stored.insert(entry.fs_path(), entry);
             }
         }
 
-        // Insert new entries
-        let mut insert_query = Vec::with_capacity(dir_hash.len());
-        for (key, value) in dir_hash.iter() {
-            // Insert
-            if !collisions.contains(key.clone()) {
-                //                tracec!("Insert entry: {}", key);
-                insert_query.push((
-                    e::location_id.eq(value.location_id),
-                    e::name.eq(&value.name),
-                    e::path.eq(&value.path),
-                    e::size.eq(value.size as i64),
-                ));
+        // *** Diff ***
+        let mut entry_updates = Vec::new();
+        let mut new_entries: HashMap<&Path, &DirEntry> = HashMap::new();
+        let mut files = FileChanges::default();
+        for (_, dir) in batch.iter() {
+            let update = location_update(updates, dir.location_id);
+            let entry = match stored.get(&dir.path) {
+                Some(entry) => entry,
+                None => {
+                    update.entries.push(change(
+                        ChangeKind::Added,
+                        dir.path.clone(),
+                        dir.size as i64,
+                    ));
+                    new_entries.insert(&dir.path, dir);
+                    continue;
+                }
+            };
+
+            if diff_entry(entry, dir, updates) {
+                entry_updates.push((entry.id, dir));
             }
+            files.touch(entry.id, dir);
+            let stored_files = self
+                .filesCache
+                .get(&entry.id)
+                .map_or(&[][..], Vec::as_slice);
+            let update = location_update(updates, entry.location_id);
+            diff_files(stored_files, entry.id, dir, update, &mut files);
         }
+        let seen: Vec<i32> = stored.values().map(|entry| entry.id).collect();
 
-        diesel::insert_into(e::entries)
-            .values(&insert_query)
-            .execute(&mut connection)
-            .expect("Failed to execute entry insert query");
+        // *** Write ***
+        connection.transaction::<_, Error, _>(|conn| {
+            for (id, dir) in entry_updates.iter() {
+                write_entry_update(conn, *id, dir)?;
+            }
+            for slice in seen.chunks(5000) {
+                diesel::update(e::entries.filter(e::id.eq_any(slice)))
+                    .set(e::scan_generation.eq(generation))
+                    .execute(conn)?;
+            }
 
-        // Reload entries cache
-        self.entriesCache = e::entries
-            .load(&mut connection)
-            .expect("Failed to load entries");
+            let last_id: Option<i32> = e::entries.select(max(e::id)).first(conn)?;
+            let new_dirs: Vec<&DirEntry> = new_entries.values().copied().collect();
+            insert_entries(conn, &new_dirs, generation)?;
+
+            // New entries got their ids, all their files are new
+            let inserted: Vec<Entry> = e::entries
+                .filter(e::id.gt(last_id.unwrap_or(0)))
+                .load(conn)?;
+            for entry in inserted.iter() {
+                if let Some(dir) = new_entries.get(entry.fs_path().as_path()) {
+                    files.add_all(entry.id, dir);
+                }
+            }
 
-        //        debug!("Entries: {} dirs: {}", self.entriesCache.len(), dir_hash.len());
+            write_files(conn, &files)
+        })?;
 
-        // *** Start files updates ***
-        let mut insert_query = Vec::new();
+        self.reload_entries(&files.touched)
+    }
 
-        for entry in self.entriesCache.iter() {
-            let dir = dir_hash.get(&entry.path).expect(&format!(
-                "Failed to find dir when updating files: {}",
-                entry.path
-            ));
+    /// Ends the streamed scan of a location. If it was complete, the entries it did not see are
+    /// paired with entries added by this update, dropped if they were regrouped or else marked
+    /// missing. The location status and totals are written once here.
+    fn finish_location(
+        &mut self,
+        report: &ScanReport,
+        generation: i32,
+        last_id: i32,
+        updates: &mut HashMap<i32, LocationUpdate>,
+    ) -> Result<()> {
+        use diesel::result::Error;
 
-            let mut file_lookup = HashSet::new();
+        let location_id = report.location_id;
+        let now = unix_now();
+        let purge_before = now - self.missing_retention.as_secs() as i64;
+
+        let connection = self.connection.get_mut();
+        let location_entries = e::entries.filter(e::location_id.eq(location_id));
+        let mut unseen: HashSet<i32> = HashSet::new();
+        if report.is_complete() {
+            unseen = location_entries
+                .filter(e::scan_generation.ne(generation))
+                .select(e::id)
+                .load::<i32>(connection)?
+                .into_iter()
+                .collect();
+        }
+
+        // *** Entries added by this update that replace an unseen entry ***
+        let mut added = Vec::new();
+        if !unseen.is_empty() {
+            let first_new = self.entriesCache.partition_point(|e| e.id <= last_id);
+            let candidates: Vec<&Entry> = self.entriesCache[first_new..]
+                .iter()
+                .filter(|entry| entry.location_id == location_id)
+                .collect();
+            let ids: Vec<i32> = candidates.iter().map(|entry| entry.id).collect();
+
+            let mut dirs: HashMap<i32, Vec<(PathBuf, i64)>> = HashMap::new();
+            for slice in ids.chunks(5000) {
+                for mtime in dm::dir_mtimes
+                    .filter(dm::entry_id.eq_any(slice))
+                    .load::<DirMtime>(connection)?
+                {
+                    dirs.entry(mtime.entry_id)
+                        .or_default()
+                        .push((mtime.fs_path(), mtime.mtime));
+                }
+            }
 
-            if let Some(file_cache) = self.filesCache.get(&entry.id) {
-                // Entry already exists
+            for entry in candidates {
+                let files = self
+                    .filesCache
+                    .get(&entry.id)
+                    .map_or(&[][..], Vec::as_slice);
+                let dirs = dirs.remove(&entry.id).unwrap_or_default();
+                added.push((entry.id, stored_dir(entry, files, dirs)));
+            }
+        }
+        let added_ids: HashMap<&Path, i32> = added
+            .iter()
+            .map(|(id, dir)| (dir.path.as_path(), *id))
+            .collect();
+        let new_entries: HashMap<&Path, &DirEntry> = added
+            .iter()
+            .map(|(_, dir)| (dir.path.as_path(), dir))
+            .collect();
+
+        let moved = find_moved_entries(&self.entriesCache, &self.filesCache, &unseen, &new_entries);
+
+        let mut replaced = Vec::new();
+        let mut files = FileChanges::default();
+        for entry in moved.iter() {
+            debug!("Entry moved: {:?} -> {:?}", entry.from, entry.dir.path);
+            unseen.remove(&entry.entry_id);
+            replaced.push(added_ids[entry.dir.path.as_path()]);
+            files.touch(entry.entry_id, entry.dir);
+
+            // Reported as added by its batch
+            let update = location_update(updates, entry.dir.location_id);
+            update
+                .entries
+                .retain(|c| c.kind != ChangeKind::Added || c.path != entry.dir.path);
+            let kind = ChangeKind::Moved {
+                from: entry.from.clone(),
+            };
+            update
+                .entries
+                .push(change(kind, entry.dir.path.clone(), entry.dir.size as i64));
+        }
 
-                let mut file_hash = HashMap::new();
-                for file in dir.files.iter() {
-                    file_hash.insert(file.path.clone(), file);
+        // *** Entries merged into a larger entry or split up by an entry depth change ***
+        let mut regrouped = Vec::new();
+        let mut newly_missing = Vec::new();
+        if !unseen.is_empty() {
+            let scanned: Vec<PathBuf> = location_entries
+                .filter(e::scan_generation.eq(generation))
+                .load::<Entry>(connection)?
+                .iter()
+                .map(Entry::fs_path)
+                .collect();
+            let scanned: HashSet<&Path> = scanned.iter().map(PathBuf::as_path).collect();
+            let containers: HashSet<&Path> = scanned
+                .iter()
+                .flat_map(|path| path.ancestors().skip(1))
+                .collect();
+
+            let update = location_update(updates, location_id);
+            for entry in self.entriesCache.iter() {
+                if !unseen.contains(&entry.id) {
+                    continue;
                 }
 
-                for file in file_cache.iter() {
-                    file_lookup.insert(file.path.clone());
-
-                    if let Some(oldFile) = file_hash.get(&file.path) {
-                        // File exists, check for diffs
-                        let new_size = oldFile.size as i64;
-                        if file.size != new_size {
-                            trace!("Update file: {}", oldFile.path);
-                            diesel::update(file)
-                                .set(f::size.eq(new_size))
-                                .execute(&mut connection)
-                                .expect("Failed to update file");
-                        }
-                    } else {
-                        // File were removed
-                        trace!("Delete file: {}", entry.path);
-                        diesel::delete(f::files.filter(f::id.eq(file.id)))
-                            .execute(&mut connection)
-                            .expect("Failed to delete entry");
+                let path = entry.fs_path();
+                if containers.contains(path.as_path())
+                    || path.ancestors().skip(1).any(|p| scanned.contains(p))
+                {
+                    // Not missing, its files belong to other entries now
+                    regrouped.push(entry.id);
+                    if entry.missing_since.is_none() {
+                        update
+                            .entries
+                            .push(change(ChangeKind::Removed, path, entry.size));
                     }
+                } else if entry.missing_since.is_none() {
+                    newly_missing.push(entry.id);
+                    update
+                        .entries
+                        .push(change(ChangeKind::Removed, path, entry.size));
                 }
             }
+        }
 
-            // Entry is new, insert all files
-            for file in dir.files.iter() {
-                if !file_lookup.contains(&file.path) {
-                    trace!("Insert file: {}", file.path);
-                    insert_query.push((
-                        f::entry_id.eq(entry.id),
-                        f::name.eq(&file.name),
-                        f::path.eq(&file.path),
-                        f::size.eq(file.size as i64),
-                    ));
+        // *** Write ***
+        let deleted = connection.transaction::<_, Error, _>(|conn| {
+            // The entry added in its place is merged into the moved entry
+            for slice in replaced.chunks(5000) {
+                diesel::delete(e::entries.filter(e::id.eq_any(slice))).execute(conn)?;
+            }
+            for entry in moved.iter() {
+                write_move(conn, entry)?;
+                diesel::update(e::entries.filter(e::id.eq(entry.entry_id)))
+                    .set(e::scan_generation.eq(generation))
+                    .execute(conn)?;
+            }
+            write_files(conn, &files)?;
+
+            for slice in regrouped.chunks(5000) {
+                diesel::delete(e::entries.filter(e::id.eq_any(slice))).execute(conn)?;
+            }
+            for slice in newly_missing.chunks(5000) {
+                diesel::update(e::entries.filter(e::id.eq_any(slice)))
+                    .set(e::missing_since.eq(now))
+                    .execute(conn)?;
+
+                // Rescan the entry if it shows up again at the same path
+                diesel::delete(dm::dir_mtimes.filter(dm::entry_id.eq_any(slice))).execute(conn)?;
+            }
+
+            write_scan_report(conn, report, now)?;
+
+            let purged: Vec<i32> = location_entries
+                .filter(e::missing_since.lt(purge_before))
+                .select(e::id)
+                .load(conn)?;
+            for slice in purged.chunks(5000) {
+                diesel::delete(e::entries.filter(e::id.eq_any(slice))).execute(conn)?;
+            }
+            if !purged.is_empty() {
+                info!("Purged {} entries missing for too long", purged.len());
+            }
+
+            write_location_total(conn, location_id)?;
+
+            Ok(purged)
+        })?;
+
+        for id in replaced
+            .iter()
+            .chain(regrouped.iter())
+            .chain(deleted.iter())
+        {
+            self.forget_entry(*id);
+        }
+        let changed: Vec<i32> = moved
+            .iter()
+            .map(|entry| entry.entry_id)
+            .chain(newly_missing)
+            .collect();
+        self.reload_entries(&changed)
+    }
+
+    /// Reads the rows and files of `ids` into the caches again, other entries are left alone.
+    fn reload_entries(&mut self, ids: &[i32]) -> Result<()> {
+        let connection = self.connection.get_mut();
+
+        let mut entries = Vec::with_capacity(ids.len());
+        let mut files: HashMap<i32, Vec<File>> = ids.iter().map(|id| (*id, Vec::new())).collect();
+        for slice in ids.chunks(5000) {
+            entries.extend(
+                e::entries
+                    .filter(e::id.eq_any(slice))
+                    .load::<Entry>(connection)?,
+            );
+            for file in f::files
+                .filter(f::entry_id.eq_any(slice))
+                .order(f::id)
+                .load::<File>(connection)?
+            {
+                files.entry(file.entry_id).or_default().push(file);
+            }
+        }
+
+        for entry in entries {
+            match self.entriesCache.binary_search_by_key(&entry.id, |e| e.id) {
+                Ok(ix) => self.entriesCache[ix] = entry,
+                Err(ix) => self.entriesCache.insert(ix, entry),
+            }
+        }
+        self.filesCache.extend(files);
+
+        Ok(())
+    }
+
+    /// Drops a deleted entry from the caches, its label mappings were removed by the foreign
+    /// key cascade.
+    fn forget_entry(&mut self, id: i32) {
+        if let Ok(ix) = self.entriesCache.binary_search_by_key(&id, |e| e.id) {
+            self.entriesCache.remove(ix);
+        }
+        self.filesCache.remove(&id);
+
+        if let Some(label_ids) = self.entryLabelLookup.remove(&id) {
+            for label_id in label_ids {
+                if let Some(entries) = self.labelLookupCache.get_mut(&label_id) {
+                    entries.remove(&id);
+                }
+            }
+        }
+    }
+
+    /// How long entries that vanished are kept before an update purges them.
+    pub fn set_missing_retention(&mut self, retention: Duration) {
+        self.missing_retention = retention;
+    }
+
+    /// Applies the result of `dir_search::get_changed_data`, entries skipped by the scan are
+    /// left untouched.
+    pub fn update_incremental(&mut self, delta: &ScanDelta) -> Result<UpdateReport> {
+        debug!("Starting incremental update");
+
+        let paths: HashSet<&Path> = delta.removed.iter().map(|p| p.as_path()).collect();
+        let changed: HashSet<&Path> = delta
+            .changed
+            .iter()
+            .map(|(_, dir)| dir.path.as_path())
+            .collect();
+
+        // A removed path can also be a directory that grouped several entries
+        let removed = self
+            .entriesCache
+            .iter()
+            .filter(|entry| entry.fs_path().ancestors().any(|p| paths.contains(p)))
+            .map(|entry| entry.id)
+            .collect();
+
+        self.apply_changes(&delta.changed, removed, &changed, &delta.reports)
+    }
+
+    /// Diffs the scanned entries against the cache and writes all changes in one transaction,
+    /// the database and caches are left as they were if any statement fails. Removed entries
+    /// inside or above a `scanned` path were regrouped rather than vanished. The location
+    /// status is recorded from `reports`.
+    fn apply_changes(
+        &mut self,
+        dir_entries: &[(i32, DirEntry)],
+        removed: Vec<i32>,
+        scanned: &HashSet<&Path>,
+        reports: &[ScanReport],
+    ) -> Result<UpdateReport> {
+        use diesel::result::Error;
+
+        let start = Instant::now();
+
+        let mut dir_hash: HashMap<&Path, &DirEntry> = HashMap::with_capacity(dir_entries.len());
+        let mut updates: HashMap<i32, LocationUpdate> = HashMap::new();
+
+        for (location_id, dir) in dir_entries.iter() {
+            dir_hash.insert(&dir.path, dir);
+            location_update(&mut updates, dir.location_id);
+        }
+
+        // *** Diff entries ***
+        let mut entry_updates = Vec::new();
+        let mut collisions = HashSet::new();
+        for entry in self.entriesCache.iter() {
+            let entry_path = entry.fs_path();
+            if let Some(dir_entry) = dir_hash.get(entry_path.as_path()) {
+                if diff_entry(entry, dir_entry, &mut updates) {
+                    entry_updates.push((entry.id, *dir_entry));
+                }
+                collisions.insert(entry_path);
+            }
+        }
+
+        let mut new_entries: HashMap<&Path, &DirEntry> = dir_hash
+            .iter()
+            .filter(|(key, _)| !collisions.contains(**key))
+            .map(|(key, dir)| (*key, *dir))
+            .collect();
+
+        // *** Entries moved or renamed outside the app keep their id, labels and grade ***
+        let mut removed_ids: HashSet<i32> = removed.iter().copied().collect();
+        let moved = find_moved_entries(
+            &self.entriesCache,
+            &self.filesCache,
+            &removed_ids,
+            &new_entries,
+        );
+
+        for entry in moved.iter() {
+            debug!("Entry moved: {:?} -> {:?}", entry.from, entry.dir.path);
+            removed_ids.remove(&entry.entry_id);
+            new_entries.remove(entry.dir.path.as_path());
+
+            let kind = ChangeKind::Moved {
+                from: entry.from.clone(),
+            };
+            location_update(&mut updates, entry.dir.location_id)
+                .entries
+                .push(change(kind, entry.dir.path.clone(), entry.dir.size as i64));
+        }
+        // *** Entries merged into a larger entry or split up by an entry depth change ***
+        let containers: HashSet<&Path> = scanned
+            .iter()
+            .flat_map(|path| path.ancestors().skip(1))
+            .collect();
+        let mut regrouped = Vec::new();
+        for entry in self.entriesCache.iter() {
+            if !removed_ids.contains(&entry.id) {
+                continue;
+            }
+
+            let path = entry.fs_path();
+            if containers.contains(path.as_path())
+                || path.ancestors().skip(1).any(|p| scanned.contains(p))
+            {
+                // Not missing, its files belong to other entries now
+                regrouped.push(entry.id);
+                if entry.missing_since.is_none() {
+                    location_update(&mut updates, entry.location_id)
+                        .entries
+                        .push(change(ChangeKind::Removed, path, entry.size));
                 }
             }
         }
+        for id in regrouped.iter() {
+            removed_ids.remove(id);
+        }
+
+        // Entries that were already missing stay as they are
+        let mut newly_missing = Vec::new();
+        for entry in self.entriesCache.iter() {
+            if removed_ids.contains(&entry.id) && entry.missing_since.is_none() {
+                newly_missing.push(entry.id);
+                location_update(&mut updates, entry.location_id)
+                    .entries
+                    .push(change(ChangeKind::Removed, entry.fs_path(), entry.size));
+            }
+        }
+
+        let now = unix_now();
+        let purge_before = now - self.missing_retention.as_secs() as i64;
+
+        for dir in new_entries.values() {
+            location_update(&mut updates, dir.location_id)
+                .entries
+                .push(change(ChangeKind::Added, dir.path.clone(), dir.size as i64));
+        }
+
+        // *** Diff files of existing entries ***
+        let mut files = FileChanges::default();
+        for entry in self.entriesCache.iter() {
+            let dir = match dir_hash.get(entry.fs_path().as_path()) {
+                Some(dir) => dir,
+                // Not part of this scan
+                None => continue,
+            };
+
+            files.touch(entry.id, dir);
+            let stored = self
+                .filesCache
+                .get(&entry.id)
+                .map_or(&[][..], Vec::as_slice);
+            let update = location_update(&mut updates, entry.location_id);
+            diff_files(stored, entry.id, dir, update, &mut files);
+        }
+
+        // *** Write ***
+        let connection = self.connection.get_mut();
+        let (entries, deleted) = connection.transaction::<_, Error, _>(|conn| {
+            let mut deleted = 0;
+            for slice in regrouped.chunks(5000) {
+                deleted += diesel::delete(e::entries.filter(e::id.eq_any(slice))).execute(conn)?;
+            }
+
+            for slice in newly_missing.chunks(5000) {
+                diesel::update(e::entries.filter(e::id.eq_any(slice)))
+                    .set(e::missing_since.eq(now))
+                    .execute(conn)?;
+
+                // Rescan the entry if it shows up again at the same path
+                diesel::delete(dm::dir_mtimes.filter(dm::entry_id.eq_any(slice))).execute(conn)?;
+            }
+
+            for entry in moved.iter() {
+                write_move(conn, entry)?;
+            }
+
+            for (id, dir) in entry_updates.iter() {
+                write_entry_update(conn, *id, dir)?;
+            }
+
+            let new_dirs: Vec<&DirEntry> = new_entries.values().copied().collect();
+            insert_entries(conn, &new_dirs, 0)?;
+
+            for report in reports {
+                write_scan_report(conn, report, now)?;
+            }
+
+            // After the updates so entries that came back are not purged
+            let purged = diesel::delete(e::entries.filter(e::missing_since.lt(purge_before)))
+                .execute(conn)?;
+            if purged > 0 {
+                info!("Purged {} entries missing for too long", purged);
+            }
+            deleted += purged;
+
+            let entries: Vec<Entry> = e::entries.order(e::id).load(conn)?;
+
+            // New entries got their ids, all their files are new
+            let mut files = files;
+            for entry in moved.iter() {
+                files.touch(entry.entry_id, entry.dir);
+            }
+            for entry in entries.iter() {
+                if let Some(dir) = new_entries.get(entry.fs_path().as_path()) {
+                    files.add_all(entry.id, dir);
+                }
+            }
 
-        diesel::insert_into(f::files)
-            .values(&insert_query)
-            .execute(&mut connection)
-            .expect("Failed to execute file insert query");
+            write_files(conn, &files)?;
+            write_location_totals(conn)?;
 
-        self.load_files(&mut connection);
+            Ok((entries, deleted))
+        })?;
+
+        self.entriesCache = entries;
+        self.load_files()?;
+        if deleted > 0 {
+            // Label mappings of deleted entries were removed by the foreign key cascade
+            self.load_labels()?;
+        }
 
         // Done!
         info!(
@@ -276,22 +853,134 @@ impl Store {
             self.entriesCache.len()
         );
 
-        info!("Update took: {:?} ms", start.elapsed().whole_milliseconds());
+        info!("Update took: {:?} ms", start.elapsed().as_millis());
+
+        let mut locations: Vec<LocationUpdate> = updates.into_values().collect();
+        locations.sort_by_key(|l| l.location_id);
+        for location in locations.iter_mut() {
+            location.detect_moves();
+        }
+
+        Ok(UpdateReport { locations })
+    }
+
+    /// Directory mtimes recorded by the last scan for each location, used to skip unchanged
+    /// entries in `dir_search::get_changed_data`.
+    pub fn get_known_dirs(&self) -> Result<HashMap<i32, KnownDirs>> {
+        let mut connection = self.connection.borrow_mut();
+
+        let mtimes: Vec<DirMtime> = dm::dir_mtimes.load(&mut *connection)?;
+
+        let mut entry_dirs: HashMap<i32, Vec<(PathBuf, i64)>> = HashMap::new();
+        for mtime in mtimes {
+            entry_dirs
+                .entry(mtime.entry_id)
+                .or_default()
+                .push((mtime.fs_path(), mtime.mtime));
+        }
+
+        let mut known: HashMap<i32, KnownDirs> = HashMap::new();
+        for entry in self.entriesCache.iter() {
+            known.entry(entry.location_id).or_default().insert(
+                entry.fs_path(),
+                entry_dirs.remove(&entry.id).unwrap_or_default(),
+            );
+        }
+
+        Ok(known)
     }
 
     pub fn get_all_entries(&self) -> &Vec<Entry> {
-        return &self.entriesCache;
+        &self.entriesCache
     }
 
     pub fn get_files(&self, entry: &Entry) -> Option<&Vec<File>> {
-        return self.filesCache.get(&entry.id);
+        self.filesCache.get(&entry.id)
+    }
+
+    /*** Hashes ***/
+    /// Hashes every file that shares its size with another file, returns the number of new
+    /// hashes. Files that already have a hash are not read again.
+    pub fn hash_files(&mut self, threads: usize) -> Result<usize> {
+        let candidates = self
+            .present_files()
+            .map(|file| HashCandidate {
+                file_id: file.id,
+                path: file.fs_path(),
+                size: file.size as u64,
+                hash: file.hash.clone(),
+            })
+            .collect();
+
+        let hashes = hash_candidates(candidates, threads);
+
+        let connection = self.connection.get_mut();
+        connection.transaction::<_, diesel::result::Error, _>(|conn| {
+            for (file_id, hash) in hashes.iter() {
+                diesel::update(f::files.filter(f::id.eq(file_id)))
+                    .set(f::hash.eq(hash))
+                    .execute(conn)?;
+            }
+
+            Ok(())
+        })?;
+
+        self.load_files()?;
+
+        Ok(hashes.len())
+    }
+
+    /// Files of all entries that are not missing, links to content listed elsewhere are left
+    /// out so they are not hashed or reported as duplicates.
+    fn present_files(&self) -> impl Iterator<Item = &File> {
+        self.entriesCache
+            .iter()
+            .filter(|entry| entry.missing_since.is_none())
+            .filter_map(|entry| self.filesCache.get(&entry.id))
+            .flatten()
+            .filter(|file| file.symlink_target.is_none() && !file.hard_link)
+    }
+
+    /// Groups of files with the same content hash across all entries and locations, the groups
+    /// that free up the most space come first.
+    pub fn find_duplicates(&self) -> Vec<DuplicateGroup> {
+        let mut by_hash: HashMap<&str, Vec<&File>> = HashMap::new();
+
+        for file in self.present_files() {
+            if let Some(hash) = &file.hash {
+                by_hash.entry(hash).or_default().push(file);
+            }
+        }
+
+        let mut groups: Vec<DuplicateGroup> = by_hash
+            .into_iter()
+            .filter(|(_, files)| files.len() > 1)
+            .map(|(hash, files)| {
+                let mut files: Vec<File> = files.into_iter().cloned().collect();
+                files.sort_by_key(|file| file.id);
+
+                DuplicateGroup {
+                    hash: hash.to_string(),
+                    size: files[0].size as u64,
+                    files,
+                }
+            })
+            .collect();
+
+        groups.sort_by(|a, b| {
+            b.reclaimable()
+                .cmp(&a.reclaimable())
+                .then_with(|| a.hash.cmp(&b.hash))
+        });
+        groups
     }
 
     /*** Labels ***/
-    pub fn add_entry_labels(&mut self, entry_ids: Vec<i32>, label_ids: Vec<i32>) {
+    pub fn add_entry_labels(&mut self, entry_ids: Vec<i32>, label_ids: Vec<i32>) -> Result<()> {
         use diesel::result::Error;
 
         let mut insert_query = Vec::with_capacity(entry_ids.len() * label_ids.len());
+        let mut added = Vec::with_capacity(insert_query.capacity());
 
         for entry_id in entry_ids.iter() {
             let map = self.entryLabelLookup.get(entry_id);
@@ -306,56 +995,80 @@ impl Store {
                 }
 
                 insert_query.push((e2l::entry_id.eq(entry_id), e2l::label_id.eq(label_id)));
+                added.push((*entry_id, *label_id));
             }
         }
 
-        let mut connection = self.establish_connection();
-        connection
-            .transaction::<_, Error, _>(|conn| {
-                debug!("Add labels");
+        let connection = self.connection.get_mut();
+        connection.transaction::<_, Error, _>(|conn| {
+            debug!("Add labels");
 
-                for slice in insert_query.iter().collect::<Vec<_>>().chunks(5000) {
-                    diesel::insert_into(e2l::entry2labels)
-                        .values(slice.to_vec())
-                        .execute(conn)?;
-                }
+            for slice in insert_query.iter().collect::<Vec<_>>().chunks(5000) {
+                diesel::insert_into(e2l::entry2labels)
+                    .values(slice.to_vec())
+                    .execute(conn)?;
+            }
 
-                Ok(())
-            })
-            .expect("Failed to add_entry_labels");
+            Ok(())
+        })?;
 
         debug!("add_entry_labels() All labels done");
-        self.load_labels(&mut connection);
+
+        for (entry_id, label_id) in added {
+            self.entryLabelLookup
+                .entry(entry_id)
+                .or_default()
+                .insert(label_id);
+            self.labelLookupCache
+                .entry(label_id)
+                .or_default()
+                .insert(entry_id);
+        }
+
+        Ok(())
     }
 
-    pub fn remove_entry_labels(&mut self, entry_ids: Vec<i32>, label_ids: Vec<i32>) {
+    pub fn remove_entry_labels(&mut self, entry_ids: Vec<i32>, label_ids: Vec<i32>) -> Result<()> {
         use diesel::result::Error;
 
-        let mut connection = self.establish_connection();
-        connection
-            .transaction::<_, Error, _>(|conn| {
-                // Remove labels not set
-                for entry_id in entry_ids.iter() {
-                    diesel::delete(
-                        e2l::entry2labels
-                            .filter(e2l::entry_id.eq(entry_id))
-                            .filter(e2l::label_id.eq_any(&label_ids)),
-                    )
-                    .execute(conn)?;
-                }
-                println!("Removed labels!");
-                debug!("Labels deleted");
+        let connection = self.connection.get_mut();
+        connection.transaction::<_, Error, _>(|conn| {
+            // Remove labels not set
+            for entry_id in entry_ids.iter() {
+                diesel::delete(
+                    e2l::entry2labels
+                        .filter(e2l::entry_id.eq(entry_id))
+                        .filter(e2l::label_id.eq_any(&label_ids)),
+                )
+                .execute(conn)?;
+            }
+            debug!("Labels deleted");
 
-                Ok(())
-            })
-            .expect("Failed to remvove_entry_label");
+            Ok(())
+        })?;
 
         debug!("Label done");
-        self.load_labels(&mut connection);
+
+        for entry_id in entry_ids.iter() {
+            if let Some(labels) = self.entryLabelLookup.get_mut(entry_id) {
+                for label_id in label_ids.iter() {
+                    labels.remove(label_id);
+                }
+            }
+        }
+        for label_id in label_ids.iter() {
+            if let Some(entries) = self.labelLookupCache.get_mut(label_id) {
+                for entry_id in entry_ids.iter() {
+                    entries.remove(entry_id);
+                }
+            }
+        }
+
+        Ok(())
     }
 
     pub fn entry_labels(&self, entry_id: i32) -> Option<&HashSet<i32>> {
-        return self.entryLabelLookup.get(&entry_id);
+        self.entryLabelLookup.get(&entry_id)
     }
 
     pub fn dir_labels(&self, entry_id: i32) -> Vec<i32> {
@@ -367,235 +1080,1909 @@ impl Store {
             }
         }
 
-        return labels;
+        labels
     }
 
     pub fn has_label(&self, entry_id: i32, label_id: i32) -> bool {
         if let Some(entries) = self.labelLookupCache.get(&label_id) {
             return entries.contains(&entry_id);
         }
-        return false;
+        false
     }
 
-    pub fn add_label(&mut self, name: &str) -> bool {
-        if self.labelsCache.iter().any(|lbl| &lbl.name == name) {
-            return false;
+    /// Returns false if a label with the same name already exists.
+    pub fn add_label(&mut self, name: &str) -> Result<bool> {
+        if name.trim().is_empty() {
+            return Err(OrganizerError::InvalidInput(
+                "Label name can not be empty".to_string(),
+            ));
+        }
+
+        if self.labelsCache.iter().any(|lbl| lbl.name == name) {
+            return Ok(false);
         }
 
-        let mut connection = self.establish_connection();
+        let connection = self.connection.get_mut();
         diesel::insert_into(l::labels)
             .values(l::name.eq(name))
-            .execute(&mut connection)
-            .expect("Failed to insert new label");
+            .execute(connection)?;
 
-        self.labelsCache = l::labels
-            .load(&mut connection)
-            .expect("Failed to load labels");
-        self.load_labels(&mut connection);
+        self.labelsCache = l::labels.load(connection)?;
+        self.load_labels()?;
 
-        return true;
+        Ok(true)
     }
 
-    pub fn remove_label(&mut self, id: i32) {
-        let mut connection = self.establish_connection();
+    pub fn remove_label(&mut self, id: i32) -> Result<()> {
+        let connection = self.connection.get_mut();
 
-        diesel::delete(l::labels.filter(l::id.eq(id)))
-            .execute(&mut connection)
-            .expect("Failed to delete label");
+        diesel::delete(l::labels.filter(l::id.eq(id))).execute(connection)?;
 
-        self.labelsCache = l::labels
-            .load(&mut connection)
-            .expect("Failed to load labels");
-        self.load_labels(&mut connection);
+        self.labelsCache = l::labels.load(connection)?;
+        self.load_labels()
     }
 
     pub fn get_all_labels(&self) -> &Vec<Label> {
-        return &self.labelsCache;
+        &self.labelsCache
+    }
+
+    /// Totals per location and label from the caches, missing entries are left out.
+    pub fn get_statistics(&self) -> Result<Statistics> {
+        let locations = self.get_locations()?;
+        let mut location_totals: HashMap<i32, Totals> = HashMap::new();
+        let mut label_totals: HashMap<i32, Totals> = HashMap::new();
+        let mut total = Totals::default();
+
+        for entry in self.entriesCache.iter() {
+            if entry.missing_since.is_some() {
+                continue;
+            }
+
+            let files = self
+                .filesCache
+                .get(&entry.id)
+                .map_or(&[][..], |f| f.as_slice());
+            total.add(entry, files);
+            location_totals
+                .entry(entry.location_id)
+                .or_default()
+                .add(entry, files);
+
+            for label_id in self.entry_labels(entry.id).into_iter().flatten() {
+                label_totals.entry(*label_id).or_default().add(entry, files);
+            }
+        }
+
+        Ok(Statistics {
+            locations: locations
+                .into_iter()
+                .map(|location| LocationStats {
+                    totals: location_totals.remove(&location.id).unwrap_or_default(),
+                    location,
+                })
+                .collect(),
+            labels: self
+                .labelsCache
+                .iter()
+                .map(|label| LabelStats {
+                    label: label.clone(),
+                    totals: label_totals.remove(&label.id).unwrap_or_default(),
+                })
+                .collect(),
+            total,
+        })
     }
 
     /*** Locations ***/
-    pub fn add_location(&mut self, name: &str, path: &str) {
-        let mut connection = self.establish_connection();
+    pub fn add_location(&mut self, name: &str, path: &str) -> Result<()> {
+        if path.trim().is_empty() {
+            return Err(OrganizerError::InvalidInput(
+                "Location path can not be empty".to_string(),
+            ));
+        }
+
+        let connection = self.connection.get_mut();
         diesel::insert_into(loc::locations)
             .values((loc::name.eq(name), loc::path.eq(path), loc::size.eq(0)))
-            .execute(&mut connection)
-            .expect("Failed to insert new location");
-    }
-
-    pub fn remove_location(&mut self, id: i32) {
-        let mut connection = self.establish_connection();
+            .execute(connection)?;
 
-        diesel::delete(loc::locations.filter(loc::id.eq(id)))
-            .execute(&mut connection)
-            .expect("Failed to delete location");
+        Ok(())
     }
 
-    pub fn get_locations(&self) -> Vec<Location> {
-        let mut connection = self.establish_connection();
+    pub fn remove_location(&mut self, id: i32) -> Result<()> {
+        let connection = self.connection.get_mut();
 
-        let locations = loc::locations
-            .load(&mut connection)
-            .expect("Failed to load locations");
-        return locations;
+        let count = diesel::delete(loc::locations.filter(loc::id.eq(id))).execute(connection)?;
+
+        if count == 0 {
+            return Err(OrganizerError::NotFound(format!("Location {}", id)));
+        }
+
+        Ok(())
+    }
+
+    /// Sets how items of a location are grouped into entries, see `Location::entry_depth`. The
+    /// next update replaces the entries of the old layout.
+    pub fn set_entry_layout(
+        &mut self,
+        id: i32,
+        entry_depth: i32,
+        file_entries: bool,
+    ) -> Result<()> {
+        if entry_depth < 1 {
+            return Err(OrganizerError::InvalidInput(format!(
+                "Entry depth must be at least 1, got {}",
+                entry_depth
+            )));
+        }
+
+        let connection = self.connection.get_mut();
+
+        let count = diesel::update(loc::locations.filter(loc::id.eq(id)))
+            .set((
+                loc::entry_depth.eq(entry_depth),
+                loc::file_entries.eq(file_entries),
+            ))
+            .execute(connection)?;
+
+        if count == 0 {
+            return Err(OrganizerError::NotFound(format!("Location {}", id)));
+        }
+
+        Ok(())
     }
 
-    pub fn move_file_to_dir(&mut self, entry: &Entry, new_entry_name: &str, new_path: &str) {
-        let mut connection = self.establish_connection();
+    /// Replaces the glob patterns of a location, see `path_filter::PathFilter`. All entries of
+    /// the location are walked again by the next incremental scan.
+    pub fn set_location_globs(
+        &mut self,
+        id: i32,
+        include: &[&str],
+        exclude: &[&str],
+    ) -> Result<()> {
+        use diesel::result::Error;
+
+        validate_patterns(include)?;
+        validate_patterns(exclude)?;
+
+        let connection = self.connection.get_mut();
+        let count = connection.transaction::<_, Error, _>(|conn| {
+            let count = diesel::update(loc::locations.filter(loc::id.eq(id)))
+                .set((
+                    loc::include_globs.eq(include.join("\n")),
+                    loc::exclude_globs.eq(exclude.join("\n")),
+                ))
+                .execute(conn)?;
+
+            forget_location_mtimes(conn, id)?;
+
+            Ok(count)
+        })?;
 
-        let path = Path::new(&new_path);
-        let new_name = path.file_name().unwrap().to_str().unwrap();
+        if count == 0 {
+            return Err(OrganizerError::NotFound(format!("Location {}", id)));
+        }
+
+        Ok(())
+    }
+
+    /// All entries of the location are walked again by the next incremental scan.
+    pub fn set_symlink_policy(&mut self, id: i32, policy: SymlinkPolicy) -> Result<()> {
+        use diesel::result::Error;
+
+        let connection = self.connection.get_mut();
+        let count = connection.transaction::<_, Error, _>(|conn| {
+            let count = diesel::update(loc::locations.filter(loc::id.eq(id)))
+                .set(loc::symlinks.eq(policy as i32))
+                .execute(conn)?;
+            forget_location_mtimes(conn, id)?;
+
+            Ok(count)
+        })?;
+
+        if count == 0 {
+            return Err(OrganizerError::NotFound(format!("Location {}", id)));
+        }
+
+        Ok(())
+    }
+
+    pub fn get_locations(&self) -> Result<Vec<Location>> {
+        let mut connection = self.connection.borrow_mut();
+
+        let locations = loc::locations.load(&mut *connection)?;
+        Ok(locations)
+    }
+
+    pub fn move_file_to_dir(
+        &mut self,
+        entry: &Entry,
+        new_entry_name: &str,
+        new_path: &Path,
+    ) -> Result<()> {
+        let connection = self.connection.get_mut();
 
-        let entry_path = path.parent().unwrap().to_string_lossy();
+        let bad_path = || OrganizerError::InvalidInput(format!("Bad file path: {:?}", new_path));
+        let new_name = new_path.file_name().ok_or_else(bad_path)?.to_string_lossy();
+        let entry_path = new_path.parent().ok_or_else(bad_path)?;
 
         // Update file
         let file = self
-            .get_files(&entry)
-            .expect("Failed to find file when renaming entry")
-            .iter()
-            .next()
-            .expect("Failed to find file when renaming entry");
-
-        diesel::update(file)
-            .set((f::name.eq(new_name), f::path.eq(new_path)))
-            .execute(&mut connection)
-            .expect("Failed to update path of file");
+            .filesCache
+            .get_mut(&entry.id)
+            .and_then(|files| files.first_mut())
+            .ok_or_else(|| OrganizerError::NotFound(format!("File for entry {}", entry.path)))?;
+
+        diesel::update(&*file)
+            .set((
+                f::name.eq(&new_name),
+                f::path.eq(new_path.to_string_lossy()),
+                f::path_raw.eq(raw_path(new_path)),
+            ))
+            .execute(connection)?;
+
+        file.name = new_name.into_owned();
+        file.set_fs_path(new_path);
 
         // Update entry
-        diesel::update(entry)
-            .set((e::name.eq(new_entry_name), e::path.eq(entry_path)))
-            .execute(&mut connection)
-            .expect("Failed to update name of entry");
+        let count = diesel::update(entry)
+            .set((
+                e::name.eq(new_entry_name),
+                e::path.eq(entry_path.to_string_lossy()),
+                e::path_raw.eq(raw_path(entry_path)),
+            ))
+            .execute(connection)?;
+
+        if count == 0 {
+            return Err(OrganizerError::NotFound(format!("Entry {}", entry.id)));
+        }
+
+        if let Some(cached) = self.cached_entry_mut(entry.id) {
+            cached.name = new_entry_name.to_string();
+            cached.set_fs_path(entry_path);
+        }
 
-        self.load_from_store();
+        Ok(())
     }
 
     pub fn rename_entry(
         &mut self,
         entry: Entry,
         new_entry_name: &str,
-        new_path: &str,
+        new_path: &Path,
         is_file_entry: bool,
-    ) {
-        let mut connection = self.establish_connection();
-
-        let path = Path::new(&new_path);
-        let new_name = path.file_name().unwrap().to_str().unwrap();
+    ) -> Result<()> {
+        let connection = self.connection.get_mut();
 
         let mut files = self
-            .get_files(&entry)
-            .expect("Failed to find file when renaming entry")
-            .into_iter();
+            .filesCache
+            .get_mut(&entry.id)
+            .ok_or_else(|| OrganizerError::NotFound(format!("Files for entry {}", entry.path)))?
+            .iter_mut();
 
         // Update file
         if !is_file_entry {
             // Entry is a folder, move all files in folder.
+            let old_path = entry.fs_path();
 
             for file in files {
-                let mut path = PathBuf::from(&new_path);
-                path.push(&file.name);
-
-                println!(
-                    "Update path of file: {:?} to {:?}",
-                    file.name,
-                    path.to_string_lossy()
-                );
-                diesel::update(file)
-                    .set(f::path.eq(path.to_string_lossy()))
-                    .execute(&mut connection)
-                    .expect("Failed to update path of file");
+                let file_path = file.fs_path();
+                let path = match file_path.strip_prefix(&old_path) {
+                    Ok(rel) => new_path.join(rel),
+                    Err(_) => new_path.join(file_path.file_name().unwrap_or_default()),
+                };
+
+                debug!("Update path of file: {:?} to {:?}", file.name, path);
+                diesel::update(&*file)
+                    .set((
+                        f::path.eq(path.to_string_lossy()),
+                        f::path_raw.eq(raw_path(&path)),
+                    ))
+                    .execute(connection)?;
+
+                file.set_fs_path(&path);
             }
         } else {
             // Entry is just a file, change file paths and name
-            let file = files.next().expect("Failed to find file for entry");
-            diesel::update(file)
-                .set((f::name.eq(new_entry_name), f::path.eq(new_path)))
-                .execute(&mut connection)
-                .expect("Failed to update path of file");
+            let file = files.next().ok_or_else(|| {
+                OrganizerError::NotFound(format!("File for entry {}", entry.path))
+            })?;
+            diesel::update(&*file)
+                .set((
+                    f::name.eq(new_entry_name),
+                    f::path.eq(new_path.to_string_lossy()),
+                    f::path_raw.eq(raw_path(new_path)),
+                ))
+                .execute(connection)?;
+
+            file.name = new_entry_name.to_string();
+            file.set_fs_path(new_path);
         }
 
         // Update entry
-        diesel::update(&entry)
-            .set((e::name.eq(new_entry_name), e::path.eq(new_path)))
-            .execute(&mut connection)
-            .expect("Failed to update name of entry");
+        let count = diesel::update(&entry)
+            .set((
+                e::name.eq(new_entry_name),
+                e::path.eq(new_path.to_string_lossy()),
+                e::path_raw.eq(raw_path(new_path)),
+            ))
+            .execute(connection)?;
+
+        if count == 0 {
+            return Err(OrganizerError::NotFound(format!("Entry {}", entry.id)));
+        }
+
+        if let Some(cached) = self.cached_entry_mut(entry.id) {
+            cached.name = new_entry_name.to_string();
+            cached.set_fs_path(new_path);
+        }
 
-        self.load_from_store();
+        Ok(())
     }
 
-    pub fn set_grade(&mut self, entry: Entry, grade: i32) {
-        let mut connection = self.establish_connection();
+    pub fn set_grade(&mut self, entry: Entry, grade: i32) -> Result<()> {
+        let connection = self.connection.get_mut();
 
         // Update entry
-        diesel::update(&entry)
+        let count = diesel::update(&entry)
             .set(e::grade.eq(grade))
-            .execute(&mut connection)
-            .expect("Failed to update name of entry");
+            .execute(connection)?;
 
-        self.load_from_store();
+        if count == 0 {
+            return Err(OrganizerError::NotFound(format!("Entry {}", entry.id)));
+        }
+
+        if let Some(cached) = self.cached_entry_mut(entry.id) {
+            cached.grade = Some(grade);
+        }
+
+        Ok(())
     }
 
-    pub fn remove_entry(&mut self, id: i32) {
-        let mut connection = self.establish_connection();
+    pub fn remove_entry(&mut self, id: i32) -> Result<()> {
+        let location_id = self.cached_entry_mut(id).map(|entry| entry.location_id);
+        let connection = self.connection.get_mut();
 
-        diesel::delete(e::entries.filter(e::id.eq(id)))
-            .execute(&mut connection)
-            .expect("Failed to delete entry");
+        let count = diesel::delete(e::entries.filter(e::id.eq(id))).execute(connection)?;
 
-        self.load_from_store();
-        self.load_labels(&mut connection);
+        if count == 0 {
+            return Err(OrganizerError::NotFound(format!("Entry {}", id)));
+        }
+        if let Some(location_id) = location_id {
+            write_location_total(connection, location_id)?;
+        }
+
+        // Label mappings are removed by the foreign key cascade
+        self.forget_entry(id);
+
+        Ok(())
     }
 
-    pub fn remove_file(&mut self, id: i32) {
-        let mut connection = self.establish_connection();
+    pub fn remove_file(&mut self, id: i32) -> Result<()> {
+        let Some((entry_id, size)) = self
+            .filesCache
+            .values()
+            .flatten()
+            .find(|f| f.id == id)
+            // Links to content counted elsewhere did not add to the entry size
+            .map(|file| (file.entry_id, if file.hard_link { 0 } else { file.size }))
+        else {
+            return Err(OrganizerError::NotFound(format!("File {}", id)));
+        };
+        let entry = self
+            .entriesCache
+            .binary_search_by_key(&entry_id, |e| e.id)
+            .ok()
+            .map(|ix| {
+                (
+                    self.entriesCache[ix].location_id,
+                    self.entriesCache[ix].size - size,
+                )
+            });
+
+        let connection = self.connection.get_mut();
+        connection.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::delete(f::files.filter(f::id.eq(id))).execute(conn)?;
+            if let Some((location_id, size)) = entry {
+                diesel::update(e::entries.filter(e::id.eq(entry_id)))
+                    .set(e::size.eq(size))
+                    .execute(conn)?;
+                write_location_total(conn, location_id)?;
+            }
+            Ok(())
+        })?;
 
-        diesel::delete(f::files.filter(f::id.eq(id)))
-            .execute(&mut connection)
-            .expect("Failed to delete file");
+        if let Some(files) = self.filesCache.get_mut(&entry_id) {
+            files.retain(|f| f.id != id);
+        }
+        if let (Some(cached), Some((_, size))) = (self.cached_entry_mut(entry_id), entry) {
+            cached.size = size;
+        }
 
-        self.load_from_store();
-        self.load_labels(&mut connection);
+        Ok(())
     }
 
-    pub fn get_label_filters(&self) -> Vec<LabelAutoFilter> {
-        let mut connection = self.establish_connection();
+    /// Entry in `entriesCache`, which is kept sorted by id.
+    fn cached_entry_mut(&mut self, id: i32) -> Option<&mut Entry> {
+        let ix = self.entriesCache.binary_search_by_key(&id, |e| e.id).ok()?;
+        self.entriesCache.get_mut(ix)
+    }
 
-        let filters: Vec<LabelAutoFilter> = aut::label_auto_filters
-            .load(&mut connection)
-            .expect("Failed to load label filters");
+    pub fn get_label_filters(&self) -> Result<Vec<LabelAutoFilter>> {
+        let mut connection = self.connection.borrow_mut();
+
+        let filters: Vec<LabelAutoFilter> = aut::label_auto_filters.load(&mut *connection)?;
 
         debug!("Got {} auto filters", filters.len());
-        filters
+        Ok(filters)
     }
 
     // *** Label filters ***
 
-    pub fn add_update_label_filter(&mut self, filter: &LabelAutoFilter) {
-        use std::convert::From;
-        let mut connection = self.establish_connection();
+    pub fn add_update_label_filter(&mut self, filter: &LabelAutoFilter) -> Result<()> {
+        let connection = self.connection.get_mut();
 
         if filter.id > 0 {
             // Update
             diesel::update(aut::label_auto_filters)
                 .set(filter)
-                .execute(&mut connection)
-                .expect("Failed to update label filter");
+                .execute(connection)?;
         } else {
             // Add
             let insertable = LabelAutoFilterInsert::new(filter);
             diesel::insert_into(aut::label_auto_filters)
                 .values(insertable)
-                .execute(&mut connection)
-                .expect("Failed to insert new label filter");
+                .execute(connection)?;
         }
+
+        Ok(())
     }
 
-    pub fn delete_label_filter(&mut self, filter: &LabelAutoFilter) {
-        use std::convert::From;
-        let mut connection = self.establish_connection();
+    pub fn delete_label_filter(&mut self, filter: &LabelAutoFilter) -> Result<()> {
+        let connection = self.connection.get_mut();
 
         // Delete
         diesel::delete(aut::label_auto_filters.filter(aut::id.eq(filter.id)))
-            .execute(&mut connection)
-            .expect("Failed to delete label filter");
+            .execute(connection)?;
+
+        Ok(())
+    }
+}
+
+/// An entry that disappeared from its path and showed up with the same files elsewhere.
+struct MovedEntry<'a> {
+    entry_id: i32,
+    from: PathBuf,
+    dir: &'a DirEntry,
+    /// Stored file id for each scanned file
+    files: Vec<(i32, &'a FileEntry)>,
+}
+
+/// Relative path and size of every file, identifies an entry wherever it is. A file entry has no
+/// relative path so its file name is used instead.
+type Signature = Vec<(PathBuf, u64)>;
+
+fn signature_key(entry_path: &Path, file_path: &Path) -> PathBuf {
+    match file_path.strip_prefix(entry_path) {
+        Ok(rel) if !rel.as_os_str().is_empty() => rel.to_path_buf(),
+        _ => PathBuf::from(file_path.file_name().unwrap_or_default()),
+    }
+}
+
+fn entry_signature(entry: &Entry, files: &HashMap<i32, Vec<File>>) -> Signature {
+    let entry_path = entry.fs_path();
+    let mut signature: Signature = files
+        .get(&entry.id)
+        .into_iter()
+        .flatten()
+        .map(|f| (signature_key(&entry_path, &f.fs_path()), f.size as u64))
+        .collect();
+
+    signature.sort();
+    signature
+}
+
+fn dir_signature(dir: &DirEntry) -> Signature {
+    let mut signature: Signature = dir
+        .files
+        .iter()
+        .map(|f| (signature_key(&dir.path, &f.path), f.size))
+        .collect();
+
+    signature.sort();
+    signature
+}
+
+/// Signatures without any content can't tell entries apart.
+fn is_blank(signature: &Signature) -> bool {
+    signature.iter().all(|(_, size)| *size == 0)
+}
+
+/// The only file of a file entry.
+fn single_file<'a>(entry: &Entry, files: &'a HashMap<i32, Vec<File>>) -> Option<&'a File> {
+    match files.get(&entry.id).map(Vec::as_slice) {
+        Some([file]) if file.fs_path() == entry.fs_path() => Some(file),
+        _ => None,
+    }
+}
+
+/// Pairs removed entries with new entries that have the same relative file set and sizes, or for
+/// file entries the same name and size. Stored content hashes have to match as well. A renamed
+/// file entry is only paired through its stored hash. Only unambiguous pairs are returned, empty
+/// entries are never paired.
+fn find_moved_entries<'a>(
+    entries: &[Entry],
+    files: &HashMap<i32, Vec<File>>,
+    removed: &HashSet<i32>,
+    new_entries: &HashMap<&Path, &'a DirEntry>,
+) -> Vec<MovedEntry<'a>> {
+    let mut removed: Vec<&Entry> = entries.iter().filter(|e| removed.contains(&e.id)).collect();
+    if removed.is_empty() {
+        return Vec::new();
+    }
+
+    let mut removed_by_signature: HashMap<Signature, Vec<&Entry>> = HashMap::new();
+    for entry in removed.iter() {
+        let signature = entry_signature(entry, files);
+        if is_blank(&signature) {
+            continue;
+        }
+        removed_by_signature
+            .entry(signature)
+            .or_default()
+            .push(entry);
+    }
+
+    let mut new_by_signature: HashMap<Signature, Vec<&'a DirEntry>> = HashMap::new();
+    for dir in new_entries.values() {
+        new_by_signature
+            .entry(dir_signature(dir))
+            .or_default()
+            .push(dir);
+    }
+
+    let mut pairs: Vec<(&Entry, &'a DirEntry)> = removed_by_signature
+        .into_iter()
+        .filter_map(|(signature, old)| match new_by_signature.get(&signature) {
+            Some(dirs) if dirs.len() == 1 && old.len() == 1 => Some((old[0], dirs[0])),
+            _ => None,
+        })
+        .filter(|(entry, dir)| same_hashes(entry, dir, files))
+        .collect();
+
+    let paired_ids: HashSet<i32> = pairs.iter().map(|(entry, _)| entry.id).collect();
+    let paired: HashSet<&Path> = pairs.iter().map(|(_, dir)| dir.path.as_path()).collect();
+    removed.retain(|entry| !paired_ids.contains(&entry.id));
+    pairs.extend(renamed_files(&removed, files, new_entries, &paired));
+
+    pairs
+        .into_iter()
+        .map(|(entry, dir)| {
+            let entry_path = entry.fs_path();
+            let file_ids: HashMap<PathBuf, i32> = files
+                .get(&entry.id)
+                .into_iter()
+                .flatten()
+                .map(|f| (signature_key(&entry_path, &f.fs_path()), f.id))
+                .collect();
+
+            let files = match single_file(entry, files) {
+                // Renamed, the name is no key
+                Some(file) => vec![(file.id, &dir.files[0])],
+                None => dir
+                    .files
+                    .iter()
+                    .filter_map(|f| Some((*file_ids.get(&signature_key(&dir.path, &f.path))?, f)))
+                    .collect(),
+            };
+
+            MovedEntry {
+                entry_id: entry.id,
+                from: entry_path,
+                dir,
+                files,
+            }
+        })
+        .collect()
+}
+
+/// Every stored hash of `entry` matches the content of the same file in `dir`.
+fn same_hashes(entry: &Entry, dir: &DirEntry, files: &HashMap<i32, Vec<File>>) -> bool {
+    let entry_path = entry.fs_path();
+    let new_paths: HashMap<PathBuf, &Path> = dir
+        .files
+        .iter()
+        .map(|f| (signature_key(&dir.path, &f.path), f.path.as_path()))
+        .collect();
+
+    files
+        .get(&entry.id)
+        .into_iter()
+        .flatten()
+        .filter_map(|f| Some((signature_key(&entry_path, &f.fs_path()), f.hash.as_ref()?)))
+        .all(|(key, hash)| {
+            new_paths
+                .get(&key)
+                .and_then(|path| content_hash(path))
+                .is_some_and(|new_hash| new_hash == *hash)
+        })
+}
+
+/// Pairs removed file entries with a stored hash with new, unpaired file entries of the same
+/// size and content.
+fn renamed_files<'e, 'a>(
+    removed: &[&'e Entry],
+    files: &HashMap<i32, Vec<File>>,
+    new_entries: &HashMap<&Path, &'a DirEntry>,
+    paired: &HashSet<&Path>,
+) -> Vec<(&'e Entry, &'a DirEntry)> {
+    let mut removed_by_content: HashMap<(u64, &str), Vec<&'e Entry>> = HashMap::new();
+    for entry in removed.iter() {
+        if let Some(file) = single_file(entry, files).filter(|f| f.size > 0) {
+            if let Some(hash) = &file.hash {
+                removed_by_content
+                    .entry((file.size as u64, hash))
+                    .or_default()
+                    .push(entry);
+            }
+        }
+    }
+
+    if removed_by_content.is_empty() {
+        return Vec::new();
+    }
+    let sizes: HashSet<u64> = removed_by_content.keys().map(|(size, _)| *size).collect();
+
+    let mut new_by_content: HashMap<(u64, String), Vec<&'a DirEntry>> = HashMap::new();
+    for dir in new_entries.values() {
+        let file = match dir.files.as_slice() {
+            [file] if file.path == dir.path => file,
+            _ => continue,
+        };
+        if paired.contains(dir.path.as_path()) || !sizes.contains(&file.size) {
+            continue;
+        }
+        if let Some(hash) = content_hash(&file.path) {
+            new_by_content
+                .entry((file.size, hash))
+                .or_default()
+                .push(dir);
+        }
+    }
+
+    removed_by_content
+        .into_iter()
+        .filter_map(
+            |((size, hash), old)| match new_by_content.get(&(size, hash.to_string())) {
+                Some(dirs) if dirs.len() == 1 && old.len() == 1 => Some((old[0], dirs[0])),
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|dur| dur.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Records how a scanned entry differs from its stored row, returns true if the row needs to be
+/// written.
+fn diff_entry(entry: &Entry, dir: &DirEntry, updates: &mut HashMap<i32, LocationUpdate>) -> bool {
+    let size = dir.size as i64;
+    if entry.missing_since.is_some() {
+        // Back after being missing
+        location_update(updates, entry.location_id)
+            .entries
+            .push(change(ChangeKind::Added, entry.fs_path(), size));
+    } else if entry.size != size {
+        let kind = ChangeKind::Resized {
+            old_size: entry.size as u64,
+        };
+        location_update(updates, entry.location_id)
+            .entries
+            .push(change(kind, dir.path.clone(), size));
+    }
+
+    entry.size != size
+        || entry.created != dir.created
+        || entry.modified != dir.modified
+        || entry.missing_since.is_some()
+}
+
+/// File rows and directory mtimes to write for the scanned entries of an update.
+#[derive(Default)]
+struct FileChanges<'a> {
+    /// Stored file id, scanned file, its type and whether its content may have changed
+    updated: Vec<(i32, &'a FileEntry, Option<String>, bool)>,
+    removed: Vec<i32>,
+    added: Vec<(i32, &'a FileEntry)>,
+    /// Entries whose directory mtimes are replaced
+    touched: Vec<i32>,
+    mtimes: Vec<(i32, &'a PathBuf, i64)>,
+}
+
+impl<'a> FileChanges<'a> {
+    fn touch(&mut self, entry_id: i32, dir: &'a DirEntry) {
+        self.touched.push(entry_id);
+        self.mtimes.extend(
+            dir.dirs
+                .iter()
+                .map(|(path, mtime)| (entry_id, path, *mtime)),
+        );
+    }
+
+    /// A new entry, none of its files are stored yet.
+    fn add_all(&mut self, entry_id: i32, dir: &'a DirEntry) {
+        self.touch(entry_id, dir);
+        self.added
+            .extend(dir.files.iter().map(|file| (entry_id, file)));
+    }
+}
+
+/// Diffs the scanned files of an entry against its `stored` files.
+fn diff_files<'a>(
+    stored: &[File],
+    entry_id: i32,
+    dir: &'a DirEntry,
+    update: &mut LocationUpdate,
+    changes: &mut FileChanges<'a>,
+) {
+    let file_hash: HashMap<&Path, &FileEntry> = dir
+        .files
+        .iter()
+        .map(|file| (file.path.as_path(), file))
+        .collect();
+    let mut file_lookup = HashSet::new();
+
+    for file in stored.iter() {
+        let file_path = file.fs_path();
+
+        if let Some(new_file) = file_hash.get(file_path.as_path()) {
+            // File exists, check for diffs
+            let resized = file.size != new_file.size as i64;
+            // Rewritten in place or replaced by another file
+            let rewritten = resized
+                || file.modified != new_file.modified
+                || file.inode != new_file.inode.map(|inode| inode as i64);
+            // Types are only sniffed on request, an unchanged file keeps its type
+            let mime = match &new_file.mime {
+                None if !rewritten => file.mime.clone(),
+                mime => mime.clone(),
+            };
+            if rewritten
+                || file.created != new_file.created
+                || file.mime != mime
+                || !same_file_info(file, new_file)
+            {
+                trace!("Update file: {}", file.path);
+                changes.updated.push((file.id, *new_file, mime, rewritten));
+            }
+
+            if resized {
+                let kind = ChangeKind::Resized {
+                    old_size: file.size as u64,
+                };
+                update
+                    .files
+                    .push(change(kind, file_path.clone(), new_file.size as i64));
+            }
+        } else {
+            trace!("Delete file: {}", file.path);
+            changes.removed.push(file.id);
+            update
+                .files
+                .push(change(ChangeKind::Removed, file_path.clone(), file.size));
+        }
+
+        file_lookup.insert(file_path);
+    }
+
+    for file in dir.files.iter() {
+        if !file_lookup.contains(&file.path) {
+            trace!("Insert file: {:?}", file.path);
+            changes.added.push((entry_id, file));
+            update.files.push(change(
+                ChangeKind::Added,
+                file.path.clone(),
+                file.size as i64,
+            ));
+        }
+    }
+}
+
+/// A stored entry as the scan that added it saw it, so it can be paired like a scanned one.
+fn stored_dir(entry: &Entry, files: &[File], dirs: Vec<(PathBuf, i64)>) -> DirEntry {
+    let files = files
+        .iter()
+        .map(|file| FileEntry {
+            name: file.name.clone(),
+            path: file.fs_path(),
+            size: file.size as u64,
+            created: file.created,
+            modified: file.modified,
+            symlink_target: file.symlink_target.as_ref().map(PathBuf::from),
+            inode: file.inode.map(|inode| inode as u64),
+            hard_link: file.hard_link,
+            extension: file.extension.clone(),
+            mime: file.mime.clone(),
+            mode: file.mode.map(|mode| mode as u32),
+            owner: file.owner.map(|owner| owner as u32),
+        })
+        .collect();
+
+    DirEntry {
+        name: entry.name.clone(),
+        location_id: entry.location_id,
+        path: entry.fs_path(),
+        files,
+        size: entry.size as u64,
+        dirs,
+        created: entry.created,
+        modified: entry.modified,
+    }
+}
+
+fn write_entry_update(conn: &mut SqliteConnection, id: i32, dir: &DirEntry) -> QueryResult<()> {
+    diesel::update(e::entries.filter(e::id.eq(id)))
+        .set((
+            e::size.eq(dir.size as i64),
+            e::created.eq(dir.created),
+            e::modified.eq(dir.modified),
+            e::missing_since.eq(None::<i64>),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Moves the stored entry and its files to where the scan found them.
+fn write_move(conn: &mut SqliteConnection, entry: &MovedEntry) -> QueryResult<()> {
+    let dir = entry.dir;
+    diesel::update(e::entries.filter(e::id.eq(entry.entry_id)))
+        .set((
+            e::location_id.eq(dir.location_id),
+            e::name.eq(&dir.name),
+            e::path.eq(dir.path.to_string_lossy()),
+            e::path_raw.eq(raw_path(&dir.path)),
+            e::size.eq(dir.size as i64),
+            e::created.eq(dir.created),
+            e::modified.eq(dir.modified),
+            e::missing_since.eq(None::<i64>),
+        ))
+        .execute(conn)?;
+
+    for (file_id, file) in entry.files.iter() {
+        diesel::update(f::files.filter(f::id.eq(file_id)))
+            .set((
+                f::name.eq(&file.name),
+                f::path.eq(file.path.to_string_lossy()),
+                f::path_raw.eq(raw_path(&file.path)),
+                f::created.eq(file.created),
+                f::modified.eq(file.modified),
+            ))
+            .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+fn insert_entries(
+    conn: &mut SqliteConnection,
+    dirs: &[&DirEntry],
+    generation: i32,
+) -> QueryResult<()> {
+    let insert_query: Vec<_> = dirs
+        .iter()
+        .map(|dir| {
+            (
+                e::location_id.eq(dir.location_id),
+                e::name.eq(&dir.name),
+                e::path.eq(dir.path.to_string_lossy()),
+                e::size.eq(dir.size as i64),
+                e::path_raw.eq(raw_path(&dir.path)),
+                e::created.eq(dir.created),
+                e::modified.eq(dir.modified),
+                e::scan_generation.eq(generation),
+            )
+        })
+        .collect();
+
+    for slice in insert_query.chunks(5000) {
+        diesel::insert_into(e::entries)
+            .values(slice)
+            .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+fn write_files(conn: &mut SqliteConnection, changes: &FileChanges) -> QueryResult<()> {
+    for slice in changes.removed.chunks(5000) {
+        diesel::delete(f::files.filter(f::id.eq_any(slice))).execute(conn)?;
+    }
+
+    for (id, file, mime, rewritten) in changes.updated.iter() {
+        let target = f::files.filter(f::id.eq(id));
+        let times = (
+            f::created.eq(file.created),
+            f::modified.eq(file.modified),
+            f::symlink_target.eq(symlink_target(file)),
+            f::inode.eq(file.inode.map(|inode| inode as i64)),
+            f::hard_link.eq(file.hard_link),
+            f::extension.eq(&file.extension),
+            f::mime.eq(mime),
+            f::mode.eq(file.mode.map(|mode| mode as i32)),
+            f::owner.eq(file.owner.map(i64::from)),
+        );
+
+        if *rewritten {
+            // Content may have changed, old hash is no longer valid
+            diesel::update(target)
+                .set((
+                    f::size.eq(file.size as i64),
+                    f::hash.eq(None::<String>),
+                    times,
+                ))
+                .execute(conn)?;
+        } else {
+            diesel::update(target).set(times).execute(conn)?;
+        }
+    }
+
+    let insert_query: Vec<_> = changes
+        .added
+        .iter()
+        .map(|(entry_id, file)| {
+            (
+                f::entry_id.eq(entry_id),
+                f::name.eq(&file.name),
+                f::path.eq(file.path.to_string_lossy()),
+                f::size.eq(file.size as i64),
+                f::path_raw.eq(raw_path(&file.path)),
+                f::created.eq(file.created),
+                f::modified.eq(file.modified),
+                f::symlink_target.eq(symlink_target(file)),
+                f::inode.eq(file.inode.map(|inode| inode as i64)),
+                f::hard_link.eq(file.hard_link),
+                f::extension.eq(&file.extension),
+                f::mime.eq(&file.mime),
+                f::mode.eq(file.mode.map(|mode| mode as i32)),
+                f::owner.eq(file.owner.map(i64::from)),
+            )
+        })
+        .collect();
+
+    for slice in insert_query.chunks(5000) {
+        diesel::insert_into(f::files).values(slice).execute(conn)?;
+    }
+
+    // Replace directory mtimes of all scanned entries
+    for slice in changes.touched.chunks(5000) {
+        diesel::delete(dm::dir_mtimes.filter(dm::entry_id.eq_any(slice))).execute(conn)?;
+    }
+
+    let mtime_query: Vec<_> = changes
+        .mtimes
+        .iter()
+        .map(|(entry_id, path, mtime)| {
+            (
+                dm::entry_id.eq(entry_id),
+                dm::path.eq(path.to_string_lossy()),
+                dm::mtime.eq(mtime),
+                dm::path_raw.eq(raw_path(path)),
+            )
+        })
+        .collect();
+
+    for slice in mtime_query.chunks(5000) {
+        diesel::insert_into(dm::dir_mtimes)
+            .values(slice)
+            .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Records whether the location was online and when it was scanned completely.
+fn write_scan_report(
+    conn: &mut SqliteConnection,
+    report: &ScanReport,
+    now: i64,
+) -> QueryResult<()> {
+    let location = loc::locations.filter(loc::id.eq(report.location_id));
+    if report.offline {
+        diesel::update(location)
+            .set((
+                loc::online.eq(false),
+                loc::last_error.eq(report.error.as_deref()),
+            ))
+            .execute(conn)?;
+    } else if let Some(duration) = report.duration {
+        diesel::update(location)
+            .set((
+                loc::online.eq(true),
+                loc::last_scan.eq(now),
+                loc::scan_duration.eq(duration.as_millis() as i64),
+                loc::last_error.eq(None::<String>),
+            ))
+            .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Stores size, entry and file count of a location, counting only entries that are not missing.
+fn write_location_total(conn: &mut SqliteConnection, location_id: i32) -> QueryResult<()> {
+    use crate::schema::files::all_columns;
+
+    let entries: Vec<(i32, i64)> = e::entries
+        .filter(e::location_id.eq(location_id))
+        .filter(e::missing_since.is_null())
+        .order(e::id)
+        .select((e::id, e::size))
+        .load(conn)?;
+
+    // Files that could be hard linked from another entry
+    let mut linked: HashMap<i32, Vec<File>> = HashMap::new();
+    for file in f::files
+        .inner_join(e::entries)
+        .filter(e::location_id.eq(location_id))
+        .filter(e::missing_since.is_null())
+        .filter(f::inode.is_not_null())
+        .select(all_columns)
+        .load::<File>(conn)?
+    {
+        linked.entry(file.entry_id).or_default().push(file);
+    }
+
+    let mut inodes = HashSet::new();
+    let mut size = 0;
+    for (id, entry_size) in entries.iter() {
+        let shared = linked_size(
+            &mut inodes,
+            location_id,
+            linked.get(id).into_iter().flatten(),
+        );
+        size += (entry_size - shared).max(0);
+    }
+
+    let file_count: i64 = f::files
+        .inner_join(e::entries)
+        .filter(e::location_id.eq(location_id))
+        .filter(e::missing_since.is_null())
+        .count()
+        .get_result(conn)?;
+
+    diesel::update(loc::locations.filter(loc::id.eq(location_id)))
+        .set((
+            loc::size.eq(size),
+            loc::entry_count.eq(entries.len() as i64),
+            loc::file_count.eq(file_count),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Stores the totals of every location, see `write_location_total`.
+fn write_location_totals(conn: &mut SqliteConnection) -> QueryResult<()> {
+    let location_ids: Vec<i32> = loc::locations.select(loc::id).load(conn)?;
+    for id in location_ids {
+        write_location_total(conn, id)?;
+    }
+
+    Ok(())
+}
+
+/// Makes the next incremental scan walk every entry of the location.
+fn forget_location_mtimes(conn: &mut SqliteConnection, location_id: i32) -> QueryResult<usize> {
+    let location_entries = e::entries
+        .filter(e::location_id.eq(location_id))
+        .select(e::id);
+
+    diesel::delete(dm::dir_mtimes.filter(dm::entry_id.eq_any(location_entries))).execute(conn)
+}
+
+fn symlink_target(file: &FileEntry) -> Option<String> {
+    file.symlink_target
+        .as_ref()
+        .map(|target| target.to_string_lossy().into_owned())
+}
+
+/// Compares everything but the times, size and type.
+fn same_file_info(file: &File, scanned: &FileEntry) -> bool {
+    file.symlink_target == symlink_target(scanned)
+        && file.inode == scanned.inode.map(|inode| inode as i64)
+        && file.hard_link == scanned.hard_link
+        && file.extension == scanned.extension
+        && file.mode == scanned.mode.map(|mode| mode as i32)
+        && file.owner == scanned.owner.map(i64::from)
+}
+
+fn location_update(
+    updates: &mut HashMap<i32, LocationUpdate>,
+    location_id: i32,
+) -> &mut LocationUpdate {
+    updates
+        .entry(location_id)
+        .or_insert_with(|| LocationUpdate::new(location_id))
+}
+
+fn change(kind: ChangeKind, path: PathBuf, size: i64) -> Change {
+    Change {
+        kind,
+        path,
+        size: size as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir_entry(location_id: i32, path: &str, files: &[(&str, u64)]) -> DirEntry {
+        let path = PathBuf::from(path);
+        let files: Vec<FileEntry> = files
+            .iter()
+            .map(|(name, size)| FileEntry {
+                name: name.to_string(),
+                path: path.join(name),
+                size: *size,
+                created: None,
+                modified: None,
+                symlink_target: None,
+                inode: None,
+                hard_link: false,
+                extension: None,
+                mime: None,
+                mode: None,
+                owner: None,
+            })
+            .collect();
+
+        DirEntry {
+            name: path.file_name().unwrap().to_string_lossy().into_owned(),
+            location_id,
+            size: files.iter().map(|f| f.size).sum(),
+            dirs: vec![(path.clone(), 1)],
+            path,
+            files,
+            created: None,
+            modified: None,
+        }
+    }
+
+    /// Path and size of each entry and its files
+    type Snapshot = Vec<(String, i64, Vec<(String, i64)>)>;
+
+    fn snapshot(store: &Store) -> Snapshot {
+        store
+            .get_all_entries()
+            .iter()
+            .map(|entry| {
+                let files = store
+                    .get_files(entry)
+                    .unwrap()
+                    .iter()
+                    .map(|f| (f.path.clone(), f.size))
+                    .collect();
+                (entry.path.clone(), entry.size, files)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unusable_databases_are_reported() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        // The database can not be created
+        let missing_dir = tmp.path().join("missing/test.sqlite3");
+        let res = Store::init(missing_dir.to_str().unwrap());
+        assert!(matches!(res, Err(OrganizerError::Io { .. })));
+
+        // A directory can not be opened as a database
+        let res = Store::init(tmp.path().to_str().unwrap());
+        assert!(
+            matches!(res, Err(OrganizerError::Connection(_))),
+            "{:?}",
+            res.err()
+        );
+
+        // Not a database at all
+        let corrupt = tmp.path().join("corrupt.sqlite3");
+        std::fs::write(&corrupt, vec![b'x'; 4096]).unwrap();
+        let res = Store::init(corrupt.to_str().unwrap());
+        assert!(
+            matches!(res, Err(OrganizerError::Database(_))),
+            "{:?}",
+            res.err()
+        );
+
+        // Migrations can not be read
+        let broken = tmp.path().join("broken.sqlite3");
+        let broken = broken.to_str().unwrap();
+        SqliteConnection::establish(broken)
+            .unwrap()
+            .batch_execute("CREATE TABLE __diesel_schema_migrations (name TEXT);")
+            .unwrap();
+        let res = Store::init(broken);
+        assert!(
+            matches!(res, Err(OrganizerError::Migration(_))),
+            "{:?}",
+            res.err()
+        );
+
+        // Locked by another connection
+        let db = tmp.path().join("test.sqlite3");
+        let db = db.to_str().unwrap();
+        drop(Store::init(db).unwrap());
+        let mut other = SqliteConnection::establish(db).unwrap();
+        other
+            .batch_execute("PRAGMA locking_mode = EXCLUSIVE; BEGIN EXCLUSIVE;")
+            .unwrap();
+        let res = Store::init(db);
+        assert!(
+            matches!(res, Err(OrganizerError::Database(_))),
+            "{:?}",
+            res.err()
+        );
+    }
+
+    #[test]
+    fn invalid_input_and_unknown_ids_are_reported() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = tmp.path().join("test.sqlite3");
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+
+        let res = store.add_location("loc", " ");
+        assert!(matches!(res, Err(OrganizerError::InvalidInput(_))));
+        let res = store.remove_location(42);
+        assert!(matches!(res, Err(OrganizerError::NotFound(_))));
+
+        // Ids that were never stored or are already gone
+        store.add_location("loc", "/loc").unwrap();
+        let location_id = store.get_locations().unwrap()[0].id;
+        let dir = dir_entry(location_id, "/loc/a", &[("1", 1)]);
+        store.update(&[(location_id, dir)], &[]).unwrap();
+        let entry = store.get_all_entries()[0].clone();
+        let file_id = store.get_files(&entry).unwrap()[0].id;
+        let unknown = Entry {
+            id: entry.id + 1,
+            ..entry.clone()
+        };
+
+        let not_found = |res: Result<()>| matches!(res, Err(OrganizerError::NotFound(_)));
+        assert!(not_found(store.set_grade(unknown.clone(), 3)));
+        assert!(not_found(store.rename_entry(
+            unknown.clone(),
+            "b",
+            Path::new("/loc/b"),
+            false
+        )));
+        assert!(not_found(store.move_file_to_dir(
+            &unknown,
+            "b",
+            Path::new("/loc/b/1")
+        )));
+        assert!(not_found(store.remove_file(file_id + 1)));
+        assert!(not_found(store.remove_entry(unknown.id)));
+
+        store.remove_file(file_id).unwrap();
+        assert!(not_found(store.remove_file(file_id)));
+        store.remove_entry(entry.id).unwrap();
+        assert!(not_found(store.remove_entry(entry.id)));
+        assert!(not_found(store.set_grade(entry, 3)));
+    }
+
+    #[test]
+    fn failed_update_keeps_previous_state() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = tmp.path().join("test.sqlite3");
+        let db = db.to_str().unwrap();
+
+        let mut store = Store::init(db).unwrap();
+        store.add_location("loc", "/loc").unwrap();
+        let location_id = store.get_locations().unwrap()[0].id;
+
+        store
+            .update(
+                &[
+                    (
+                        location_id,
+                        dir_entry(location_id, "/loc/a", &[("1", 1), ("2", 2)]),
+                    ),
+                    (location_id, dir_entry(location_id, "/loc/b", &[("3", 3)])),
+                ],
+                &[],
+            )
+            .unwrap();
+        store.load_from_store().unwrap();
+        let before = snapshot(&store);
+        let known_before = store.get_known_dirs().unwrap();
+
+        // Removes b and resizes a before the insert of c fails on its unknown location
+        let res = store.update(
+            &[
+                (location_id, dir_entry(location_id, "/loc/a", &[("1", 10)])),
+                (
+                    location_id,
+                    dir_entry(location_id + 1, "/loc/c", &[("4", 4)]),
+                ),
+            ],
+            &[],
+        );
+        assert!(matches!(res, Err(OrganizerError::Database(_))));
+
+        assert_eq!(snapshot(&store), before);
+
+        let mut reloaded = Store::init(db).unwrap();
+        reloaded.load_from_store().unwrap();
+        assert_eq!(snapshot(&reloaded), before);
+        assert_eq!(reloaded.get_known_dirs().unwrap(), known_before);
+
+        // A file is only dropped from the caches once it is deleted
+        let file_id = store.get_files(&store.get_all_entries()[0]).unwrap()[0].id;
+        store
+            .establish_connection()
+            .unwrap()
+            .batch_execute(
+                "CREATE TRIGGER keep_files BEFORE DELETE ON files
+                 BEGIN SELECT RAISE(ABORT, 'kept'); END;",
+            )
+            .unwrap();
+        let res = store.remove_file(file_id);
+        assert!(matches!(res, Err(OrganizerError::Database(_))));
+        assert_eq!(snapshot(&store), before);
+    }
+
+    #[test]
+    fn update_reports_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = tmp.path().join("test.sqlite3");
+
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+        store.add_location("loc", "/loc").unwrap();
+        let location_id = store.get_locations().unwrap()[0].id;
+
+        let report = store
+            .update(
+                &[
+                    (
+                        location_id,
+                        dir_entry(location_id, "/loc/a", &[("1", 1), ("2", 2)]),
+                    ),
+                    (location_id, dir_entry(location_id, "/loc/b", &[("3", 3)])),
+                ],
+                &[],
+            )
+            .unwrap();
+        let update = report.location(location_id).unwrap();
+        assert_eq!(update.entries.len(), 2);
+        assert!(update.entries.iter().all(|c| c.kind == ChangeKind::Added));
+        assert!(update.files.is_empty());
+
+        let report = store
+            .update(
+                &[
+                    (
+                        location_id,
+                        dir_entry(location_id, "/loc/a", &[("1", 10), ("sub/2", 2), ("4", 4)]),
+                    ),
+                    (location_id, dir_entry(location_id, "/loc/c", &[])),
+                ],
+                &[],
+            )
+            .unwrap();
+        let update = report.location(location_id).unwrap();
+
+        let mut entries: Vec<_> = update
+            .entries
+            .iter()
+            .map(|c| (c.path.to_str().unwrap(), &c.kind))
+            .collect();
+        entries.sort_by_key(|(path, _)| *path);
+        assert_eq!(
+            entries,
+            vec![
+                ("/loc/a", &ChangeKind::Resized { old_size: 3 }),
+                ("/loc/b", &ChangeKind::Removed),
+                ("/loc/c", &ChangeKind::Added),
+            ]
+        );
+
+        let mut files: Vec<_> = update
+            .files
+            .iter()
+            .map(|c| (c.path.to_str().unwrap(), &c.kind))
+            .collect();
+        files.sort_by_key(|(path, _)| *path);
+        assert_eq!(
+            files,
+            vec![
+                ("/loc/a/1", &ChangeKind::Resized { old_size: 1 }),
+                ("/loc/a/4", &ChangeKind::Added),
+                (
+                    "/loc/a/sub/2",
+                    &ChangeKind::Moved {
+                        from: PathBuf::from("/loc/a/2")
+                    }
+                ),
+            ]
+        );
+
+        assert!(store
+            .update(&[], &[])
+            .unwrap()
+            .location(location_id)
+            .is_some());
+    }
+
+    #[test]
+    fn moved_entries_keep_labels_and_grade() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = tmp.path().join("test.sqlite3");
+        let root = tmp.path().join("loc");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("movie.mkv"), "movie").unwrap();
+        std::fs::write(root.join("notes.txt"), "notes").unwrap();
+        let path = |rel: &str| root.join(rel).to_string_lossy().into_owned();
+
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+        store.add_location("loc", &path("")).unwrap();
+        store.add_label("seen").unwrap();
+        let location_id = store.get_locations().unwrap()[0].id;
+        let label_id = store.get_all_labels()[0].id;
+
+        let dir = |rel: &str, files: &[(&str, u64)]| {
+            (location_id, dir_entry(location_id, &path(rel), files))
+        };
+        let single = |rel: &str, size| {
+            let mut dir = dir_entry(location_id, &path(rel), &[]);
+            dir.files = vec![FileEntry {
+                name: dir.name.clone(),
+                path: dir.path.clone(),
+                size,
+                created: None,
+                modified: None,
+                symlink_target: None,
+                inode: None,
+                hard_link: false,
+                extension: None,
+                mime: None,
+                mode: None,
+                owner: None,
+            }];
+            dir.size = size;
+            (location_id, dir)
+        };
+
+        store
+            .update(
+                &[
+                    dir("a", &[("1", 1), ("sub/2", 2)]),
+                    single("movie.mkv", 5),
+                    single("notes.txt", 5),
+                    single("song.mp3", 7),
+                    dir("other", &[("3", 3)]),
+                    dir("twin", &[("4", 4)]),
+                    dir("empty", &[("0", 0)]),
+                ],
+                &[],
+            )
+            .unwrap();
+        // Only the two real files of the same size are hashed
+        assert_eq!(store.hash_files(1).unwrap(), 2);
+
+        let by_path = |store: &Store, rel: &str| {
+            store
+                .get_all_entries()
+                .iter()
+                .find(|e| e.path == path(rel))
+                .cloned()
+                .unwrap()
+        };
+        let a = by_path(&store, "a");
+        let movie = by_path(&store, "movie.mkv");
+        let file_ids: Vec<i32> = store.get_files(&a).unwrap().iter().map(|f| f.id).collect();
+        store.set_grade(a.clone(), 5).unwrap();
+        store
+            .add_entry_labels(vec![a.id, movie.id], vec![label_id])
+            .unwrap();
+
+        std::fs::rename(root.join("movie.mkv"), root.join("Movie (2001).mkv")).unwrap();
+        std::fs::remove_file(root.join("notes.txt")).unwrap();
+        std::fs::write(root.join("todo.txt"), "todo!").unwrap();
+
+        let report = store
+            .update(
+                &[
+                    dir("b", &[("1", 1), ("sub/2", 2)]),
+                    single("Movie (2001).mkv", 5),
+                    single("todo.txt", 5),
+                    single("track.mp3", 7),
+                    dir("changed", &[("3", 4)]),
+                    dir("twin 1", &[("4", 4)]),
+                    dir("twin 2", &[("4", 4)]),
+                    dir("empty 2", &[("0", 0)]),
+                ],
+                &[],
+            )
+            .unwrap();
+        store.load_from_store().unwrap();
+
+        let moved_dir = by_path(&store, "b");
+        assert_eq!(moved_dir.id, a.id);
+        assert_eq!(moved_dir.grade, Some(5));
+        assert!(store.has_label(a.id, label_id));
+        let files = store.get_files(&moved_dir).unwrap();
+        assert_eq!(files.iter().map(|f| f.id).collect::<Vec<_>>(), file_ids);
+        assert_eq!(files[1].path, path("b/sub/2"));
+
+        // Renamed file found through its content hash
+        let moved_movie = by_path(&store, "Movie (2001).mkv");
+        assert_eq!(moved_movie.id, movie.id);
+        assert_eq!(moved_movie.name, "Movie (2001).mkv");
+        assert!(store.has_label(movie.id, label_id));
+
+        // Different content or sizes, no hash to compare, ambiguous or empty: not a move
+        for rel in ["notes.txt", "song.mp3", "other", "twin", "empty"] {
+            assert!(by_path(&store, rel).missing_since.is_some(), "{rel}");
+        }
+
+        let kinds: Vec<_> = report.locations[0]
+            .entries
+            .iter()
+            .map(|c| &c.kind)
+            .collect();
+        assert!(kinds.contains(&&ChangeKind::Moved {
+            from: PathBuf::from(path("a"))
+        }));
+        assert!(kinds.contains(&&ChangeKind::Moved {
+            from: PathBuf::from(path("movie.mkv"))
+        }));
+        assert_eq!(
+            kinds
+                .iter()
+                .filter(|kind| ***kind == ChangeKind::Added)
+                .count(),
+            6
+        );
+    }
+
+    #[test]
+    fn vanished_entries_are_kept_as_missing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = tmp.path().join("test.sqlite3");
+
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+        store.add_location("loc", "/loc").unwrap();
+        store.add_label("label").unwrap();
+        let location_id = store.get_locations().unwrap()[0].id;
+        let label_id = store.get_all_labels()[0].id;
+
+        let a = (location_id, dir_entry(location_id, "/loc/a", &[("1", 1)]));
+        let b = (location_id, dir_entry(location_id, "/loc/b", &[("2", 2)]));
+        store.update(&[a.clone(), b.clone()], &[]).unwrap();
+        let by_id = |store: &Store, id: i32| {
+            store
+                .get_all_entries()
+                .iter()
+                .find(|e| e.id == id)
+                .cloned()
+                .unwrap()
+        };
+        let entry = store
+            .get_all_entries()
+            .iter()
+            .find(|e| e.path == "/loc/a")
+            .cloned()
+            .unwrap();
+        store.set_grade(entry.clone(), 3).unwrap();
+        store
+            .add_entry_labels(vec![entry.id], vec![label_id])
+            .unwrap();
+
+        // Offline locations are left alone
+        let offline = ScanReport {
+            offline: true,
+            ..ScanReport::new(location_id)
+        };
+        assert!(store.update(&[], &[offline]).unwrap().is_empty());
+        assert!(store
+            .get_all_entries()
+            .iter()
+            .all(|e| e.missing_since.is_none()));
+
+        let report = store.update(std::slice::from_ref(&b), &[]).unwrap();
+        assert_eq!(report.locations[0].entries[0].kind, ChangeKind::Removed);
+        store.load_from_store().unwrap();
+        let missing = by_id(&store, entry.id);
+        assert_eq!(missing.id, entry.id);
+        assert!(missing.missing_since.is_some());
+        assert!(store.get_files(&missing).is_some());
+
+        // Still missing, nothing new to report
+        assert!(store
+            .update(std::slice::from_ref(&b), &[])
+            .unwrap()
+            .is_empty());
+
+        let report = store.update(&[a.clone(), b.clone()], &[]).unwrap();
+        assert_eq!(report.locations[0].entries[0].kind, ChangeKind::Added);
+        let back = by_id(&store, entry.id);
+        assert_eq!(back.id, entry.id);
+        assert_eq!(back.grade, Some(3));
+        assert_eq!(back.missing_since, None);
+        assert!(store.has_label(entry.id, label_id));
+
+        store.update(std::slice::from_ref(&b), &[]).unwrap();
+        {
+            use crate::schema::entries::dsl as e;
+            diesel::update(e::entries.find(entry.id))
+                .set(e::missing_since.eq(Some(1)))
+                .execute(store.connection.get_mut())
+                .unwrap();
+        }
+        store.update(&[b], &[]).unwrap();
+        store.load_from_store().unwrap();
+        assert_eq!(store.get_all_entries().len(), 1);
+        assert!(!store.has_label(entry.id, label_id));
+    }
+
+    #[test]
+    fn location_totals_follow_updates() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = tmp.path().join("test.sqlite3");
+
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+        store.add_location("loc", "/loc").unwrap();
+        store.add_location("other", "/other").unwrap();
+        store.add_label("label").unwrap();
+        let locations = store.get_locations().unwrap();
+        let (loc_id, other_id) = (locations[0].id, locations[1].id);
+        let label_id = store.get_all_labels()[0].id;
+
+        let a = (loc_id, dir_entry(loc_id, "/loc/a", &[("1", 1), ("2", 2)]));
+        let b = (loc_id, dir_entry(loc_id, "/loc/b", &[("3", 4)]));
+        let c = (other_id, dir_entry(other_id, "/other/c", &[("4", 8)]));
+        store.update(&[a, b.clone(), c.clone()], &[]).unwrap();
+
+        let location = store.get_locations().unwrap()[0].clone();
+        assert_eq!(
+            (location.size, location.entry_count, location.file_count),
+            (7, 2, 3)
+        );
+
+        let by_path = |store: &Store, path: &str| {
+            store
+                .get_all_entries()
+                .iter()
+                .find(|e| e.path == path)
+                .cloned()
+                .unwrap()
+        };
+        store.set_grade(by_path(&store, "/loc/a"), 2).unwrap();
+        store.set_grade(by_path(&store, "/other/c"), 5).unwrap();
+        let ids = vec![by_path(&store, "/loc/a").id, by_path(&store, "/other/c").id];
+        store.add_entry_labels(ids, vec![label_id]).unwrap();
+
+        let stats = store.get_statistics().unwrap();
+        assert_eq!(stats.total.entries, 3);
+        assert_eq!(stats.total.size, 15);
+        assert_eq!(stats.locations[0].totals.files, 3);
+        assert_eq!(stats.locations[0].totals.average_grade(), Some(2.0));
+        assert_eq!(stats.locations[1].totals.size, 8);
+        let label = &stats.labels[0].totals;
+        assert_eq!((label.entries, label.size, label.graded), (2, 11, 2));
+        assert_eq!(label.average_grade(), Some(3.5));
+
+        // Missing and removed entries are not counted
+        store.update(&[b, c], &[]).unwrap();
+        let location = store.get_locations().unwrap()[0].clone();
+        assert_eq!((location.size, location.entry_count), (4, 1));
+        assert_eq!(store.get_statistics().unwrap().labels[0].totals.entries, 1);
+
+        store.remove_entry(by_path(&store, "/other/c").id).unwrap();
+        let other = store.get_locations().unwrap()[1].clone();
+        assert_eq!((other.size, other.entry_count, other.file_count), (0, 0, 0));
+        assert_eq!(
+            store.get_statistics().unwrap().labels[0]
+                .totals
+                .average_grade(),
+            None
+        );
+
+        // Removed files no longer count towards their entry
+        let b = by_path(&store, "/loc/b");
+        let file_id = store.get_files(&b).unwrap()[0].id;
+        store.remove_file(file_id).unwrap();
+        assert_eq!(by_path(&store, "/loc/b").size, 0);
+        let location = store.get_locations().unwrap()[0].clone();
+        assert_eq!((location.size, location.file_count), (0, 0));
+        store.load_from_store().unwrap();
+        assert_eq!(by_path(&store, "/loc/b").size, 0);
+    }
+
+    #[test]
+    fn hard_links_across_entries_are_counted_once() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = tmp.path().join("test.sqlite3");
+
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+        store.add_location("loc", "/loc").unwrap();
+        store.add_location("other", "/other").unwrap();
+        let locations = store.get_locations().unwrap();
+        let (loc_id, other_id) = (locations[0].id, locations[1].id);
+
+        let linked = |location_id, path: &str, files: &[(&str, u64)]| {
+            let mut dir = dir_entry(location_id, path, files);
+            dir.files[0].inode = Some(7);
+            (location_id, dir)
+        };
+        let a = linked(loc_id, "/loc/a", &[("1", 5), ("2", 1)]);
+        let b = linked(loc_id, "/loc/b", &[("1", 5)]);
+        let c = linked(other_id, "/other/c", &[("1", 5)]);
+        store.update(&[a, b.clone(), c], &[]).unwrap();
+
+        let location = store.get_locations().unwrap()[0].clone();
+        assert_eq!((location.size, location.file_count), (6, 3));
+        let stats = store.get_statistics().unwrap();
+        assert_eq!(stats.locations[0].totals.size, 6);
+        assert_eq!(stats.locations[0].totals.files, 3);
+        // Same inode number in another location is another file
+        assert_eq!(stats.total.size, 11);
+
+        // Both entries keep their own size
+        let mut sizes: Vec<i64> = store.get_all_entries().iter().map(|e| e.size).collect();
+        sizes.sort();
+        assert_eq!(sizes, vec![5, 5, 6]);
+
+        // The remaining link is counted in full
+        store.update(&[b], &[]).unwrap();
+        assert_eq!(store.get_locations().unwrap()[0].size, 5);
+    }
+
+    #[test]
+    fn streamed_updates_are_written_in_batches() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = tmp.path().join("test.sqlite3");
+
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+        store.add_location("loc", "/loc").unwrap();
+        store.add_location("other", "/other").unwrap();
+        store.add_label("seen").unwrap();
+        let locations = store.get_locations().unwrap();
+        let (loc, other) = (locations[0].id, locations[1].id);
+        let label_id = store.get_all_labels()[0].id;
+
+        let finished = |location_id| ScanEvent::Finished(ScanReport::new(location_id));
+        let mut events = Vec::new();
+        for ix in 0..5 {
+            let path = format!("/loc/{}", ix);
+            let size = ix as u64 + 1;
+            events.push(ScanEvent::Entry(loc, dir_entry(loc, &path, &[("f", size)])));
+        }
+        events.push(finished(loc));
+        events.push(ScanEvent::Entry(
+            other,
+            dir_entry(other, "/other/x", &[("f", 9)]),
+        ));
+        events.push(finished(other));
+
+        // Only the rows of each batch are cached again, they have to match a full reload
+        let reloaded = |store: &Store| {
+            let mut fresh = Store::init(db.to_str().unwrap()).unwrap();
+            fresh.load_from_store().unwrap();
+            assert_eq!(fresh.get_all_entries(), store.get_all_entries());
+            assert_eq!(snapshot(&fresh), snapshot(store));
+            for entry in store.get_all_entries() {
+                assert_eq!(fresh.entry_labels(entry.id), store.entry_labels(entry.id));
+            }
+        };
+
+        let report = store.update_streaming(events, 2).unwrap();
+        reloaded(&store);
+        assert_eq!(store.get_all_entries().len(), 6);
+        assert_eq!(report.location(loc).unwrap().entries.len(), 5);
+        assert_eq!(store.get_locations().unwrap()[0].entry_count, 5);
+
+        let entry = |store: &Store, path: &str| {
+            store
+                .get_all_entries()
+                .iter()
+                .find(|e| e.path == path)
+                .cloned()
+        };
+        let moved = entry(&store, "/loc/0").unwrap();
+        store
+            .add_entry_labels(vec![moved.id], vec![label_id])
+            .unwrap();
+
+        // /loc/0 moved, /loc/1 grew and /loc/4 vanished, /other did not finish
+        let mut events = vec![
+            ScanEvent::Entry(loc, dir_entry(loc, "/loc/renamed", &[("f", 1)])),
+            ScanEvent::Entry(loc, dir_entry(loc, "/loc/1", &[("f", 2), ("g", 10)])),
+        ];
+        for ix in 2..4 {
+            let path = format!("/loc/{}", ix);
+            let size = ix as u64 + 1;
+            events.push(ScanEvent::Entry(loc, dir_entry(loc, &path, &[("f", size)])));
+        }
+        events.push(finished(loc));
+
+        let report = store.update_streaming(events, 1).unwrap();
+        reloaded(&store);
+        let renamed = entry(&store, "/loc/renamed").unwrap();
+        assert_eq!(renamed.id, moved.id);
+        assert!(store.has_label(renamed.id, label_id));
+        assert!(entry(&store, "/loc/4").unwrap().missing_since.is_some());
+        assert!(entry(&store, "/other/x").unwrap().missing_since.is_none());
+
+        let mut kinds: Vec<_> = report
+            .location(loc)
+            .unwrap()
+            .entries
+            .iter()
+            .map(|c| (c.path.to_str().unwrap(), &c.kind))
+            .collect();
+        kinds.sort_by_key(|(path, _)| *path);
+        assert_eq!(
+            kinds,
+            vec![
+                ("/loc/1", &ChangeKind::Resized { old_size: 2 }),
+                ("/loc/4", &ChangeKind::Removed),
+                (
+                    "/loc/renamed",
+                    &ChangeKind::Moved {
+                        from: PathBuf::from("/loc/0")
+                    }
+                ),
+            ]
+        );
+
+        // Totals are written once the location finished, missing entries are left out
+        let locations = store.get_locations().unwrap();
+        let totals = |id| {
+            let location = locations.iter().find(|l| l.id == id).unwrap();
+            (location.size, location.entry_count, location.file_count)
+        };
+        assert_eq!(totals(loc), (1 + 12 + 3 + 4, 4, 5));
+        assert_eq!(totals(other), (9, 1, 1));
+    }
+
+    #[test]
+    fn rewritten_files_lose_their_hash() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = tmp.path().join("test.sqlite3");
+        let root = tmp.path().join("loc");
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        std::fs::write(root.join("a/1"), "same").unwrap();
+        std::fs::write(root.join("a/2"), "same").unwrap();
+
+        let mut store = Store::init(db.to_str().unwrap()).unwrap();
+        store.add_location("loc", root.to_str().unwrap()).unwrap();
+        let location_id = store.get_locations().unwrap()[0].id;
+
+        let path = root.join("a");
+        let mut dir = dir_entry(location_id, path.to_str().unwrap(), &[("1", 4), ("2", 4)]);
+        store.update(&[(location_id, dir.clone())], &[]).unwrap();
+        assert_eq!(store.hash_files(1).unwrap(), 2);
+        assert_eq!(store.find_duplicates().len(), 1);
+
+        // Same size, new content and modified time
+        std::fs::write(root.join("a/2"), "diff").unwrap();
+        dir.files[1].modified = Some(1_700_000_000);
+        store.update(&[(location_id, dir)], &[]).unwrap();
+
+        let entry = store.get_all_entries()[0].clone();
+        let files = store.get_files(&entry).unwrap();
+        assert!(files.iter().find(|f| f.name == "1").unwrap().hash.is_some());
+        assert!(files.iter().find(|f| f.name == "2").unwrap().hash.is_none());
+
+        store.hash_files(1).unwrap();
+        assert!(store.find_duplicates().is_empty());
     }
 }
@@ -0,0 +1,318 @@
+use log::{debug, info, trace, warn};
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use jwalk::WalkDir;
+
+use crate::dir_search::{find_entry_paths, is_hidden, scan_entry, CancelToken, ScanState};
+use crate::error::{OrganizerError, Result};
+use crate::models::{Location, ScanDelta, ScanReport};
+use crate::path_filter::PathFilter;
+
+#[derive(Debug, Clone)]
+struct WatchedDir {
+    location_id: i32,
+    path: PathBuf,
+}
+
+/// Watches every directory of a set of locations with inotify and turns the events into
+/// `ScanDelta`s that can be applied with `Store::update_incremental`.
+pub struct Watcher {
+    inotify: Inotify,
+    locations: HashMap<i32, (Location, Arc<PathFilter>)>,
+    watches: HashMap<WatchDescriptor, WatchedDir>,
+    buffer: Vec<u8>,
+    overflowed: bool,
+}
+
+fn watch_mask() -> WatchMask {
+    WatchMask::CREATE
+        | WatchMask::DELETE
+        | WatchMask::CLOSE_WRITE
+        | WatchMask::MOVED_FROM
+        | WatchMask::MOVED_TO
+}
+
+impl Watcher {
+    pub fn new(locations: &[Location]) -> Result<Watcher> {
+        let inotify = Inotify::init().map_err(|err| OrganizerError::io("inotify", err))?;
+
+        let mut watcher = Watcher {
+            inotify,
+            locations: HashMap::new(),
+            watches: HashMap::new(),
+            buffer: vec![0; 64 * 1024],
+            overflowed: false,
+        };
+
+        for location in locations.iter() {
+            watcher.watch_tree(location.id, Path::new(&location.path));
+            let filter = Arc::new(PathFilter::new(location));
+            watcher
+                .locations
+                .insert(location.id, (location.clone(), filter));
+        }
+
+        info!("Watching {} directories", watcher.watches.len());
+
+        Ok(watcher)
+    }
+
+    /// Adds a watch for `path` and every directory below it. Directories that can not be
+    /// watched are logged and skipped.
+    fn watch_tree(&mut self, location_id: i32, path: &Path) {
+        for entry in WalkDir::new(path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_dir())
+        {
+            let dir_path = entry.path();
+
+            match self.inotify.watches().add(&dir_path, watch_mask()) {
+                Ok(wd) => {
+                    self.watches.insert(
+                        wd,
+                        WatchedDir {
+                            location_id,
+                            path: dir_path,
+                        },
+                    );
+                }
+                Err(err) => warn!("Failed to watch {:?}: {}", dir_path, err),
+            }
+        }
+    }
+
+    /// True if the kernel dropped events since the last call, the watched locations should
+    /// then be rescanned with `dir_search::get_changed_data`.
+    pub fn take_overflowed(&mut self) -> bool {
+        std::mem::take(&mut self.overflowed)
+    }
+
+    /// Reads all pending events without blocking. Events are coalesced per entry, each touched
+    /// entry is rescanned once or reported as removed if it is gone. Events on a directory above
+    /// the entry depth rescan all entries in it.
+    pub fn poll(&mut self) -> Result<ScanDelta> {
+        let mut touched: BTreeSet<(i32, PathBuf)> = BTreeSet::new();
+        let mut new_dirs = Vec::new();
+        let mut dropped = Vec::new();
+
+        loop {
+            let events = match self.inotify.read_events(&mut self.buffer) {
+                Ok(events) => events,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(OrganizerError::io("inotify", err)),
+            };
+
+            for event in events {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    warn!("Inotify queue overflowed, events were lost");
+                    self.overflowed = true;
+                    continue;
+                }
+
+                let dir = match self.watches.get(&event.wd) {
+                    Some(dir) => dir,
+                    None => continue,
+                };
+
+                if event.mask.contains(EventMask::IGNORED) {
+                    // Directory was removed or unmounted
+                    dropped.push(event.wd.clone());
+                    continue;
+                }
+
+                let path = match event.name {
+                    Some(name) => dir.path.join(name),
+                    None => dir.path.clone(),
+                };
+                trace!("Watch event {:?} on {:?}", event.mask, path);
+
+                if event.mask.contains(EventMask::ISDIR)
+                    && (event.mask.contains(EventMask::CREATE)
+                        || event.mask.contains(EventMask::MOVED_TO))
+                {
+                    new_dirs.push((dir.location_id, path.clone()));
+                }
+
+                let (location, _) = &self.locations[&dir.location_id];
+                if let Some(entry_path) = entry_for(location, &path) {
+                    touched.insert((dir.location_id, entry_path));
+                }
+            }
+        }
+
+        for wd in dropped {
+            self.watches.remove(&wd);
+        }
+
+        for (location_id, path) in new_dirs {
+            self.watch_tree(location_id, &path);
+        }
+
+        let mut delta = ScanDelta::default();
+        let mut reports: HashMap<i32, ScanReport> = HashMap::new();
+
+        for (location_id, entry_path) in touched {
+            if !entry_path.exists() {
+                delta.removed.push(entry_path);
+                continue;
+            }
+
+            let report = reports
+                .entry(location_id)
+                .or_insert_with(|| ScanReport::new(location_id));
+
+            let (location, filter) = &self.locations[&location_id];
+            let mut state = ScanState::new(location_id, None, CancelToken::new());
+            let entry_paths = if is_container(location, &entry_path) {
+                find_entry_paths(location, filter, &entry_path, &mut state, report)
+                    .unwrap_or_default()
+            } else {
+                vec![entry_path]
+            };
+
+            for entry_path in entry_paths {
+                let symlinks = location.symlink_policy();
+                if let Some(dir) = scan_entry(
+                    location_id,
+                    &entry_path,
+                    filter,
+                    symlinks,
+                    &mut state,
+                    report,
+                ) {
+                    delta.changed.push((location_id, dir));
+                }
+            }
+        }
+
+        delta.reports = reports.into_values().filter(|r| !r.is_empty()).collect();
+
+        if !delta.changed.is_empty() || !delta.removed.is_empty() {
+            debug!(
+                "Watcher found {} changed and {} removed entries",
+                delta.changed.len(),
+                delta.removed.len()
+            );
+        }
+
+        Ok(delta)
+    }
+}
+
+/// Maps a path somewhere inside a location to its entry, or to the directory above the entry
+/// depth that contains it.
+fn entry_for(location: &Location, path: &Path) -> Option<PathBuf> {
+    let location_path = Path::new(&location.path);
+    let relative = path.strip_prefix(location_path).ok()?;
+    if relative.as_os_str().is_empty() {
+        return None;
+    }
+
+    let depth = if location.file_entries {
+        usize::MAX
+    } else {
+        location.entry_depth.max(1) as usize
+    };
+    let entry: PathBuf = relative.components().take(depth).collect();
+
+    // Hidden items are not scanned, at any level
+    if entry.iter().any(|name| is_hidden(Path::new(name))) {
+        return None;
+    }
+
+    Some(location_path.join(entry))
+}
+
+/// True for existing directories that group entries instead of being one.
+fn is_container(location: &Location, path: &Path) -> bool {
+    let depth = path
+        .strip_prefix(&location.path)
+        .map_or(0, |relative| relative.components().count() as i32);
+
+    (location.file_entries || depth < location.entry_depth) && path.is_dir()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn location(root: &Path) -> Location {
+        Location {
+            id: 1,
+            name: "loc".to_string(),
+            path: root.to_str().unwrap().to_string(),
+            size: 0,
+            online: true,
+            last_scan: None,
+            scan_duration: None,
+            last_error: None,
+            entry_count: 0,
+            file_count: 0,
+            entry_depth: 1,
+            file_entries: false,
+            include_globs: String::new(),
+            exclude_globs: String::new(),
+            symlinks: 1,
+        }
+    }
+
+    #[test]
+    fn events_are_coalesced_per_entry() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("gone.txt"), "x").unwrap();
+
+        let location = location(root);
+        let mut watcher = Watcher::new(&[location]).unwrap();
+
+        fs::create_dir_all(root.join("a/sub")).unwrap();
+        fs::write(root.join("a/one.txt"), "1").unwrap();
+        fs::write(root.join("a/two.txt"), "22").unwrap();
+        fs::remove_file(root.join("gone.txt")).unwrap();
+
+        let delta = watcher.poll().unwrap();
+        assert_eq!(delta.changed.len(), 1);
+        assert_eq!(delta.changed[0].1.name, "a");
+        assert_eq!(delta.changed[0].1.size, 3);
+        assert_eq!(delta.removed, vec![root.join("gone.txt")]);
+
+        // New sub directories are watched as well
+        fs::write(root.join("a/sub/three.txt"), "333").unwrap();
+        let delta = watcher.poll().unwrap();
+        assert_eq!(delta.changed.len(), 1);
+        assert_eq!(delta.changed[0].1.files.len(), 3);
+    }
+
+    #[test]
+    fn events_map_to_entries_at_depth() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("artist/album")).unwrap();
+
+        let mut location = location(root);
+        location.entry_depth = 2;
+        let mut watcher = Watcher::new(&[location]).unwrap();
+
+        fs::write(root.join("artist/album/1.flac"), "1").unwrap();
+        fs::create_dir_all(root.join("new/first")).unwrap();
+        fs::create_dir_all(root.join("new/second")).unwrap();
+
+        let delta = watcher.poll().unwrap();
+        let mut names: Vec<_> = delta.changed.iter().map(|(_, d)| d.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["album", "first", "second"]);
+
+        fs::remove_dir_all(root.join("new")).unwrap();
+        let delta = watcher.poll().unwrap();
+        assert!(delta.changed.is_empty());
+        assert!(delta.removed.contains(&root.join("new")));
+    }
+}